@@ -0,0 +1,166 @@
+use crate::budget::{Budget, Interrupted};
+use crate::sieve::Sieve;
+use crate::{primality, special, verified};
+use clap::ValueEnum;
+use rug::Integer;
+use rug::integer::IsPrime;
+use std::sync::Arc;
+use tracing::debug;
+
+// Rounds of Miller-Rabin GMP runs on top of its own BPSW
+const GMP_REPS: u32 = 30;
+
+/// A way of answering `isPrime`
+pub trait PrimalityTest: Send + Sync {
+    fn is_prime(&self, n: &Integer, budget: &Budget) -> Result<bool, Interrupted>;
+}
+
+/// The primality tests to pick from on the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Backend {
+    /// The sieve, then proven trial division, then Miller-Rabin, special
+    /// forms and BPSW, each where it's quickest
+    Auto,
+    /// Division by everything up to the square root
+    TrialDivision,
+    /// GMP's probable-prime test
+    Gmp,
+    /// Deterministic Miller-Rabin for u64, BPSW past that
+    Bpsw,
+    /// The startup sieve, falling back to BPSW past its limit
+    Sieve,
+}
+
+impl Backend {
+    /// The test, looking small numbers up in `sieve` where it uses one.
+    /// `Sieve` needs one; `Config::validate` makes sure there is.
+    pub fn build(self, sieve: Option<Arc<Sieve>>) -> Arc<dyn PrimalityTest> {
+        match self {
+            Self::Auto => Arc::new(Auto { sieve }),
+            Self::TrialDivision => Arc::new(TrialDivision),
+            Self::Gmp => Arc::new(Gmp),
+            Self::Bpsw => Arc::new(Bpsw),
+            Self::Sieve => Arc::new(Lookup {
+                sieve: sieve.expect("--primality sieve without a sieve"),
+            }),
+        }
+    }
+}
+
+struct Auto {
+    sieve: Option<Arc<Sieve>>,
+}
+
+impl PrimalityTest for Auto {
+    fn is_prime(&self, n: &Integer, budget: &Budget) -> Result<bool, Interrupted> {
+        let small = n.to_u64();
+        if let Some(prime) = small.and_then(|n| self.sieve.as_ref()?.get(n)) {
+            return Ok(prime);
+        }
+        match small {
+            // Small enough that the proven check stays cheap
+            Some(n) if n < primality::VERIFIED_BELOW => Ok(verified::is_prime_sqrt(n)),
+            Some(_) => primality::is_prime(n, budget),
+            None => match special::detect(n) {
+                Some(form) => {
+                    debug!("Testing a {form:?} number");
+                    special::is_prime(n, &form, budget)
+                }
+                None => primality::is_prime(n, budget),
+            },
+        }
+    }
+}
+
+struct TrialDivision;
+
+impl PrimalityTest for TrialDivision {
+    fn is_prime(&self, n: &Integer, budget: &Budget) -> Result<bool, Interrupted> {
+        match n.to_u64() {
+            Some(n) if n < primality::VERIFIED_BELOW => Ok(verified::is_prime_sqrt(n)),
+            _ => primality::trial_division(n, budget),
+        }
+    }
+}
+
+struct Gmp;
+
+impl PrimalityTest for Gmp {
+    fn is_prime(&self, n: &Integer, _: &Budget) -> Result<bool, Interrupted> {
+        // GMP tests the absolute value
+        Ok(*n > 1 && n.is_probably_prime(GMP_REPS) != IsPrime::No)
+    }
+}
+
+struct Bpsw;
+
+impl PrimalityTest for Bpsw {
+    fn is_prime(&self, n: &Integer, budget: &Budget) -> Result<bool, Interrupted> {
+        primality::is_prime(n, budget)
+    }
+}
+
+struct Lookup {
+    sieve: Arc<Sieve>,
+}
+
+impl PrimalityTest for Lookup {
+    fn is_prime(&self, n: &Integer, budget: &Budget) -> Result<bool, Interrupted> {
+        match n.to_u64().and_then(|n| self.sieve.get(n)) {
+            Some(prime) => Ok(prime),
+            None => primality::is_prime(n, budget),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Covers only part of the range tested, so both sides of the limit get
+    // checked
+    const SIEVE_LIMIT: u64 = 10_000;
+
+    fn backends() -> Vec<(Backend, Arc<dyn PrimalityTest>)> {
+        let sieve = Arc::new(Sieve::new(SIEVE_LIMIT));
+        Backend::value_variants()
+            .iter()
+            .map(|&backend| (backend, backend.build(Some(sieve.clone()))))
+            .collect()
+    }
+
+    #[test]
+    fn backends_agree() {
+        let budget = Budget::unlimited();
+        let backends = backends();
+        for n in -100..2 * SIEVE_LIMIT as i64 {
+            let n = Integer::from(n);
+            let expected = n > 1 && n.is_probably_prime(50) != IsPrime::No;
+            for (backend, test) in &backends {
+                assert_eq!(test.is_prime(&n, &budget), Ok(expected), "{backend:?} {n}");
+            }
+        }
+        let big = [
+            (Integer::from(u64::MAX - 58), true),
+            ((Integer::from(1) << 127u32) - 1, true),
+            ((Integer::from(1) << 128u32) + 1, false),
+        ];
+        for (n, expected) in big {
+            for (backend, test) in backends
+                .iter()
+                .filter(|(b, _)| *b != Backend::TrialDivision)
+            {
+                assert_eq!(test.is_prime(&n, &budget), Ok(expected), "{backend:?} {n}");
+            }
+        }
+    }
+
+    #[test]
+    fn trial_division_respects_budget() {
+        let budget = Budget::unlimited();
+        budget.cancel();
+        let n = (Integer::from(1) << 127u32) - 1;
+        let test = Backend::TrialDivision.build(None);
+        assert_eq!(test.is_prime(&n, &budget), Err(Interrupted::Cancelled));
+    }
+}
@@ -0,0 +1,78 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// How long a single computation may run, and a flag to stop it early.
+///
+/// Long-running algorithms call [`Budget::check`] between steps; the check is
+/// cooperative, so a single big-integer operation always runs to completion.
+#[derive(Debug, Clone)]
+pub struct Budget {
+    deadline: Option<Instant>,
+    cancelled: Arc<AtomicBool>,
+}
+
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupted {
+    #[error("Ran out of time")]
+    OutOfTime,
+    #[error("Cancelled")]
+    Cancelled,
+}
+
+impl Budget {
+    pub fn unlimited() -> Self {
+        Self {
+            deadline: None,
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Self {
+            deadline: Some(Instant::now() + timeout),
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn check(&self) -> Result<(), Interrupted> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            Err(Interrupted::Cancelled)
+        } else if self.deadline.is_some_and(|d| Instant::now() >= d) {
+            Err(Interrupted::OutOfTime)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn unlimited_never_runs_out() {
+        assert_eq!(Budget::unlimited().check(), Ok(()));
+    }
+
+    #[test]
+    fn timeout_and_cancel() {
+        let budget = Budget::with_timeout(Duration::ZERO);
+        assert_eq!(budget.check(), Err(Interrupted::OutOfTime));
+
+        let budget = Budget::with_timeout(Duration::from_secs(60));
+        let clone = budget.clone();
+        assert_eq!(budget.check(), Ok(()));
+        clone.cancel();
+        assert_eq!(budget.check(), Err(Interrupted::Cancelled));
+    }
+}
@@ -0,0 +1,135 @@
+use lru::LruCache;
+use rug::Integer;
+use std::collections::HashSet;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Primality answers shared by every connection.
+///
+/// Recent answers live in a bounded LRU. Primes below `pin_below` go in a
+/// separate set instead and are never evicted; there are few enough of them
+/// that they don't need a bound of their own.
+pub struct PrimeCache {
+    recent: Mutex<LruCache<Integer, bool>>,
+    pinned: Mutex<HashSet<u64>>,
+    pin_below: u64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub entries: usize,
+    pub pinned: usize,
+}
+
+impl PrimeCache {
+    pub fn new(capacity: NonZeroUsize, pin_below: u64) -> Self {
+        Self {
+            recent: Mutex::new(LruCache::new(capacity)),
+            pinned: Mutex::new(HashSet::new()),
+            pin_below,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, n: &Integer) -> Option<bool> {
+        let pinned = self
+            .pinned_key(n)
+            .is_some_and(|n| self.pinned.lock().unwrap().contains(&n));
+        let answer = if pinned {
+            Some(true)
+        } else {
+            self.recent.lock().unwrap().get(n).copied()
+        };
+        let counter = if answer.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        answer
+    }
+
+    pub fn insert(&self, n: Integer, prime: bool) {
+        if prime && let Some(small) = self.pinned_key(&n) {
+            self.pinned.lock().unwrap().insert(small);
+            return;
+        }
+        let evicted = self.recent.lock().unwrap().push(n.clone(), prime);
+        // `push` hands back the old entry when the key was already present
+        if evicted.is_some_and(|(old, _)| old != n) {
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            entries: self.recent.lock().unwrap().len(),
+            pinned: self.pinned.lock().unwrap().len(),
+        }
+    }
+
+    fn pinned_key(&self, n: &Integer) -> Option<u64> {
+        n.to_u64().filter(|&n| n < self.pin_below)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cache(capacity: usize, pin_below: u64) -> PrimeCache {
+        PrimeCache::new(NonZeroUsize::new(capacity).unwrap(), pin_below)
+    }
+
+    #[test]
+    fn counts_hits_and_misses() {
+        let cache = cache(4, 0);
+        let n = Integer::from(1_000_003);
+        assert_eq!(cache.get(&n), None);
+        cache.insert(n.clone(), true);
+        assert_eq!(cache.get(&n), Some(true));
+        assert_eq!(cache.get(&n), Some(true));
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (2, 1, 1));
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = cache(2, 0);
+        for n in [4, 6, 8] {
+            cache.insert(Integer::from(n), false);
+        }
+        assert_eq!(cache.get(&Integer::from(4)), None);
+        assert_eq!(cache.get(&Integer::from(8)), Some(false));
+        // Refreshing an entry that is already there evicts nothing
+        cache.insert(Integer::from(8), false);
+        assert_eq!(cache.stats().evictions, 1);
+    }
+
+    #[test]
+    fn small_primes_are_pinned() {
+        let cache = cache(1, 100);
+        cache.insert(Integer::from(97), true);
+        cache.insert(Integer::from(101), true);
+        cache.insert(Integer::from(91), false);
+        cache.insert(Integer::from(103), true);
+        assert_eq!(cache.get(&Integer::from(97)), Some(true));
+        // Composites below the threshold are cached like anything else
+        assert_eq!(cache.get(&Integer::from(91)), None);
+        assert_eq!(cache.get(&Integer::from(101)), None);
+        let stats = cache.stats();
+        assert_eq!((stats.pinned, stats.entries, stats.evictions), (1, 1, 2));
+    }
+}
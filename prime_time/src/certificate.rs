@@ -0,0 +1,264 @@
+use rug::Integer;
+use rug::ops::Pow;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Primes below this go without a proof; trial division settles them quickly
+pub const SMALL: u64 = 1 << 32;
+
+/// A proof that a number is prime, which [`Certificate::verify`] checks
+/// without trusting whoever made it.
+///
+/// The big kinds rest on Pocklington's criterion. Say `F` divides `n - 1`, and
+/// for each prime `q` dividing `F` some `a` has `a^(n-1) = 1 (mod n)` and
+/// `gcd(a^((n-1)/q) - 1, n) = 1`. Then every prime factor of `n` is
+/// `1 (mod F)`, so if `F^2 > n` the only one is `n` itself.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Certificate {
+    /// Below [`SMALL`], to be checked by trial division
+    Small(#[serde(with = "integer")] Integer),
+    /// `n - 1` factored completely
+    Pratt {
+        #[serde(with = "integer")]
+        n: Integer,
+        factors: Vec<Factor>,
+    },
+    /// `n - 1` factored past its square root
+    Pocklington {
+        #[serde(with = "integer")]
+        n: Integer,
+        factors: Vec<Factor>,
+    },
+}
+
+/// A prime power dividing `n - 1`, with proof the prime is one, and the `a`
+/// that satisfies Pocklington's criterion for it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Factor {
+    pub certificate: Certificate,
+    pub exponent: u32,
+    #[serde(with = "integer")]
+    pub witness: Integer,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum Invalid {
+    #[error("{0} is too big to be checked by trial division")]
+    NotSmall(Integer),
+    #[error("{0} is not prime")]
+    Composite(Integer),
+    #[error("The factors given for {0} - 1 don't divide it")]
+    NotFactors(Integer),
+    #[error("The factors given for {0} - 1 don't make up all of it")]
+    Incomplete(Integer),
+    #[error("The factors given for {0} - 1 don't reach its square root")]
+    TooLittle(Integer),
+    #[error("{witness} is no witness for the factor {q} of {n} - 1")]
+    BadWitness {
+        n: Integer,
+        q: Integer,
+        witness: Integer,
+    },
+}
+
+impl Certificate {
+    /// The number proven prime
+    pub fn n(&self) -> &Integer {
+        match self {
+            Self::Small(n) | Self::Pratt { n, .. } | Self::Pocklington { n, .. } => n,
+        }
+    }
+
+    /// Checks this proof and every one it relies on
+    pub fn verify(&self) -> Result<(), Invalid> {
+        match self {
+            Self::Small(n) => match n.to_u64().filter(|&n| n < SMALL) {
+                Some(m) if is_small_prime(m) => Ok(()),
+                Some(_) => Err(Invalid::Composite(n.clone())),
+                None => Err(Invalid::NotSmall(n.clone())),
+            },
+            Self::Pratt { n, factors } => pocklington(n, factors, true),
+            Self::Pocklington { n, factors } => pocklington(n, factors, false),
+        }
+    }
+}
+
+fn pocklington(n: &Integer, factors: &[Factor], complete: bool) -> Result<(), Invalid> {
+    if *n < 2 {
+        return Err(Invalid::Composite(n.clone()));
+    }
+    let m = Integer::from(n - 1);
+    let bits = u64::from(m.significant_bits());
+    let mut f = Integer::from(1);
+    for factor in factors {
+        // q^e is at least 2^((bits(q) - 1) * e), so anything past that bound
+        // can't divide n - 1. Checking it first keeps a hostile exponent from
+        // building a number bigger than n out of nothing.
+        let q = factor.certificate.n();
+        let floor = u64::from(q.significant_bits().saturating_sub(1)) * u64::from(factor.exponent);
+        if *q < 2 || floor > bits {
+            return Err(Invalid::NotFactors(n.clone()));
+        }
+        factor.certificate.verify()?;
+        f *= Integer::from(q.pow(factor.exponent));
+        if f > m {
+            return Err(Invalid::NotFactors(n.clone()));
+        }
+    }
+    if !m.is_divisible(&f) {
+        return Err(Invalid::NotFactors(n.clone()));
+    }
+    if complete && f != m {
+        return Err(Invalid::Incomplete(n.clone()));
+    }
+    if Integer::from(f.square_ref()) <= *n {
+        return Err(Invalid::TooLittle(n.clone()));
+    }
+    for factor in factors {
+        let q = factor.certificate.n();
+        let a = &factor.witness;
+        let fermat = Integer::from(a.pow_mod_ref(&m, n).unwrap());
+        let partial: Integer = Integer::from(a.pow_mod_ref(&Integer::from(&m / q), n).unwrap()) - 1;
+        if fermat != 1 || partial.gcd(n) != 1 {
+            return Err(Invalid::BadWitness {
+                n: n.clone(),
+                q: q.clone(),
+                witness: a.clone(),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn is_small_prime(n: u64) -> bool {
+    n >= 2
+        && (2..)
+            .take_while(|d| d * d <= n)
+            .all(|d| !n.is_multiple_of(d))
+}
+
+/// Integers as plain JSON numbers, however big
+mod integer {
+    use rug::Integer;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer, de, ser};
+
+    pub fn serialize<S: Serializer>(n: &Integer, serializer: S) -> Result<S::Ok, S::Error> {
+        let n: serde_json::Number = n.to_string().parse().map_err(ser::Error::custom)?;
+        n.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Integer, D::Error> {
+        let n = serde_json::Number::deserialize(deserializer)?;
+        n.as_str().parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn small(n: u64) -> Certificate {
+        Certificate::Small(n.into())
+    }
+
+    fn factor(q: u64, exponent: u32, witness: u32) -> Factor {
+        Factor {
+            certificate: small(q),
+            exponent,
+            witness: witness.into(),
+        }
+    }
+
+    fn pratt(n: u64, factors: Vec<Factor>) -> Certificate {
+        Certificate::Pratt {
+            n: n.into(),
+            factors,
+        }
+    }
+
+    #[test]
+    fn small_primes() {
+        assert_eq!(small(2).verify(), Ok(()));
+        assert_eq!(small(4_294_967_291).verify(), Ok(()));
+        assert_eq!(small(1).verify(), Err(Invalid::Composite(1.into())));
+        assert_eq!(small(91).verify(), Err(Invalid::Composite(91.into())));
+        assert_eq!(small(SMALL).verify(), Err(Invalid::NotSmall(SMALL.into())));
+    }
+
+    #[test]
+    fn pratt_and_pocklington() {
+        // 7 - 1 = 2 * 3; 3 has order 6 and 2 has order 3
+        assert_eq!(
+            pratt(7, vec![factor(2, 1, 3), factor(3, 1, 2)]).verify(),
+            Ok(())
+        );
+        assert_eq!(
+            pratt(7, vec![factor(2, 1, 2), factor(3, 1, 2)]).verify(),
+            Err(Invalid::BadWitness {
+                n: 7.into(),
+                q: 2.into(),
+                witness: 2.into()
+            })
+        );
+        assert_eq!(
+            pratt(7, vec![factor(3, 1, 2)]).verify(),
+            Err(Invalid::Incomplete(7.into()))
+        );
+        assert_eq!(
+            pratt(7, vec![factor(5, 1, 2)]).verify(),
+            Err(Invalid::NotFactors(7.into()))
+        );
+        // 3 is above sqrt(7), but 2 isn't
+        let pocklington = |factors| Certificate::Pocklington {
+            n: 7.into(),
+            factors,
+        };
+        assert_eq!(pocklington(vec![factor(3, 1, 2)]).verify(), Ok(()));
+        assert_eq!(
+            pocklington(vec![factor(2, 1, 3)]).verify(),
+            Err(Invalid::TooLittle(7.into()))
+        );
+    }
+
+    #[test]
+    fn huge_exponents_rejected_early() {
+        // 2^u32::MAX would take half a gigabyte to write down
+        assert_eq!(
+            pratt(7, vec![factor(2, u32::MAX, 3)]).verify(),
+            Err(Invalid::NotFactors(7.into()))
+        );
+        // Each power fits, but their product doesn't
+        let many = (0..100_000).map(|_| factor(3, 1, 2)).collect();
+        assert_eq!(pratt(7, many).verify(), Err(Invalid::NotFactors(7.into())));
+    }
+
+    #[test]
+    fn composites_cannot_pass() {
+        // 561 - 1 = 2^4 * 5 * 7, and 561 is a Carmichael number
+        for a in 2..561u32 {
+            let certificate = pratt(561, vec![factor(2, 4, a), factor(5, 1, a), factor(7, 1, a)]);
+            assert!(certificate.verify().is_err(), "{a}");
+        }
+        // A sub-proof that fails sinks the whole certificate
+        let certificate = pratt(13, vec![factor(4, 1, 2), factor(3, 1, 2)]);
+        assert_eq!(certificate.verify(), Err(Invalid::Composite(4.into())));
+    }
+
+    #[test]
+    fn json() {
+        let certificate = pratt(7, vec![factor(2, 1, 3), factor(3, 1, 2)]);
+        let json = serde_json::to_string(&certificate).unwrap();
+        assert_eq!(
+            json,
+            r#"{"pratt":{"n":7,"factors":[{"certificate":{"small":2},"exponent":1,"witness":3},{"certificate":{"small":3},"exponent":1,"witness":2}]}}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<Certificate>(&json).unwrap(),
+            certificate
+        );
+        let big = r#"{"small":123456789012345678901234567890}"#;
+        let certificate: Certificate = serde_json::from_str(big).unwrap();
+        assert_eq!(serde_json::to_string(&certificate).unwrap(), big);
+    }
+}
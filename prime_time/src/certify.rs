@@ -0,0 +1,128 @@
+use crate::budget::{Budget, Interrupted};
+use crate::{factor, primality};
+use prime_time::certificate::{Certificate, Factor, SMALL};
+use rug::Integer;
+
+// Up to here n - 1 is factored completely, which rho does in moments; past it
+// only as far as Pocklington needs, since what's left could be a semiprime
+// too big to split in time
+const PRATT_BITS: u32 = 64;
+
+/// A certificate that `n` is prime, or `None` if it isn't
+pub fn certify(n: &Integer, budget: &Budget) -> Result<Option<Certificate>, Interrupted> {
+    if !primality::is_prime(n, budget)? {
+        return Ok(None);
+    }
+    prove(n, budget).map(Some)
+}
+
+/// Certifies `n`, which is already known to be prime
+fn prove(n: &Integer, budget: &Budget) -> Result<Certificate, Interrupted> {
+    if *n < SMALL {
+        return Ok(Certificate::Small(n.clone()));
+    }
+    let m = Integer::from(n - 1);
+    let complete = n.significant_bits() <= PRATT_BITS;
+    let (found, _) = factor::factor_until(&m, budget, |f| {
+        !complete && Integer::from(f.square_ref()) > *n
+    })?;
+    let factors = found
+        .into_iter()
+        .map(|(q, exponent)| {
+            Ok(Factor {
+                witness: witness(n, &m, &q, budget)?,
+                certificate: prove(&q, budget)?,
+                exponent,
+            })
+        })
+        .collect::<Result<_, _>>()?;
+    let n = n.clone();
+    Ok(if complete {
+        Certificate::Pratt { n, factors }
+    } else {
+        Certificate::Pocklington { n, factors }
+    })
+}
+
+/// The smallest `a` with `a^(m/q) != 1 (mod n)`. As `n` is prime, that is
+/// all Pocklington asks of it, and most `a` will do.
+fn witness(n: &Integer, m: &Integer, q: &Integer, budget: &Budget) -> Result<Integer, Interrupted> {
+    let exponent = Integer::from(m / q);
+    for a in 2u32.. {
+        budget.check()?;
+        let a = Integer::from(a);
+        if Integer::from(a.pow_mod_ref(&exponent, n).unwrap()) != 1 {
+            return Ok(a);
+        }
+    }
+    unreachable!("A prime has primitive roots")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn certify(n: &Integer) -> Option<Certificate> {
+        super::certify(n, &Budget::unlimited()).unwrap()
+    }
+
+    #[test]
+    fn composites() {
+        for n in [0, 1, 4, 561, -7] {
+            assert_eq!(certify(&n.into()), None, "{n}");
+        }
+        let m: Integer = (Integer::from(1) << 67u32) - 1;
+        assert_eq!(certify(&m), None);
+    }
+
+    #[test]
+    fn small_and_pratt() {
+        assert_eq!(certify(&97.into()), Some(Certificate::Small(97.into())));
+        for n in [
+            (Integer::from(1) << 61u32) - 1,
+            Integer::from(u64::MAX - 58),
+        ] {
+            let certificate = certify(&n).unwrap();
+            assert!(matches!(certificate, Certificate::Pratt { .. }), "{n}");
+            assert_eq!(certificate.n(), &n);
+            assert_eq!(certificate.verify(), Ok(()), "{n}");
+        }
+    }
+
+    #[test]
+    fn pocklington_past_64_bits() {
+        for n in [
+            (Integer::from(1) << 89u32) - 1,
+            (Integer::from(1) << 127u32) - 1,
+        ] {
+            let certificate = certify(&n).unwrap();
+            assert!(
+                matches!(certificate, Certificate::Pocklington { .. }),
+                "{n}"
+            );
+            assert_eq!(certificate.n(), &n);
+            assert_eq!(certificate.verify(), Ok(()), "{n}");
+        }
+    }
+
+    #[test]
+    fn pocklington() {
+        // n - 1 has a big power of two in it, so half of it comes easily
+        let base = Integer::from(1) << 300u32;
+        let n = (1u32..)
+            .map(|k| Integer::from(&base * k) + 1)
+            .find(|n| primality::is_prime(n, &Budget::unlimited()).unwrap())
+            .unwrap();
+        let certificate = certify(&n).unwrap();
+        assert!(matches!(certificate, Certificate::Pocklington { .. }));
+        assert_eq!(certificate.verify(), Ok(()));
+    }
+
+    #[test]
+    fn respects_budget() {
+        let budget = Budget::unlimited();
+        budget.cancel();
+        let n = (Integer::from(1) << 127u32) - 1;
+        assert_eq!(super::certify(&n, &budget), Err(Interrupted::Cancelled));
+    }
+}
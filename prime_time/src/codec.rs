@@ -0,0 +1,167 @@
+use bytes::BytesMut;
+use thiserror::Error;
+
+/// Incremental decoder for newline-delimited frames.
+///
+/// Bytes are appended to a caller-owned buffer as they arrive; each call to
+/// [`LineCodec::decode`] yields at most one complete line (without its
+/// trailing `\n`) and leaves any partial line in the buffer for next time.
+#[derive(Debug)]
+pub struct LineCodec {
+    max_length: usize,
+    // How much of the buffer has already been scanned without finding a newline
+    next_index: usize,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum Error {
+    #[error("Line exceeded maximum length of {0} bytes")]
+    LineTooLong(usize),
+}
+
+impl LineCodec {
+    pub fn new(max_length: usize) -> Self {
+        Self {
+            max_length,
+            next_index: 0,
+        }
+    }
+
+    pub fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<BytesMut>, Error> {
+        match buf[self.next_index..].iter().position(|b| *b == b'\n') {
+            Some(offset) => {
+                let newline_idx = self.next_index + offset;
+                self.next_index = 0;
+                if newline_idx > self.max_length {
+                    return Err(Error::LineTooLong(self.max_length));
+                }
+                let line = buf.split_to(newline_idx);
+                let _ = buf.split_to(1); // Drop the trailing newline
+                Ok(Some(line))
+            }
+            None => {
+                if buf.len() > self.max_length {
+                    return Err(Error::LineTooLong(self.max_length));
+                }
+                self.next_index = buf.len();
+                Ok(None)
+            }
+        }
+    }
+
+    /// Called once the underlying stream is exhausted: a final line without a
+    /// trailing newline is still yielded.
+    pub fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<BytesMut>, Error> {
+        match self.decode(buf)? {
+            Some(line) => Ok(Some(line)),
+            None if buf.is_empty() => Ok(None),
+            None => {
+                self.next_index = 0;
+                Ok(Some(buf.split()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn decode_chunks(codec: &mut LineCodec, chunks: &[&[u8]]) -> Result<Vec<Vec<u8>>, Error> {
+        let mut buf = BytesMut::new();
+        let mut lines = vec![];
+        for chunk in chunks {
+            buf.extend_from_slice(chunk);
+            while let Some(line) = codec.decode(&mut buf)? {
+                lines.push(line.to_vec());
+            }
+        }
+        while let Some(line) = codec.decode_eof(&mut buf)? {
+            lines.push(line.to_vec());
+        }
+        Ok(lines)
+    }
+
+    #[test]
+    fn empty_input() {
+        let mut codec = LineCodec::new(16);
+        assert_eq!(decode_chunks(&mut codec, &[]), Ok(vec![]));
+        assert_eq!(decode_chunks(&mut codec, &[b""]), Ok(vec![]));
+    }
+
+    #[test]
+    fn newline_at_start() {
+        let mut codec = LineCodec::new(16);
+        assert_eq!(
+            decode_chunks(&mut codec, &[b"\nabc\n"]),
+            Ok(vec![b"".to_vec(), b"abc".to_vec()])
+        );
+    }
+
+    #[test]
+    fn partial_line_is_kept() {
+        let mut codec = LineCodec::new(16);
+        let mut buf = BytesMut::from(&b"ab"[..]);
+        assert_eq!(codec.decode(&mut buf), Ok(None));
+        buf.extend_from_slice(b"c\nd");
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), &b"abc"[..]);
+        assert_eq!(codec.decode(&mut buf), Ok(None));
+        assert_eq!(&buf[..], b"d");
+    }
+
+    #[test]
+    fn trailing_line_at_eof() {
+        let mut codec = LineCodec::new(16);
+        assert_eq!(
+            decode_chunks(&mut codec, &[b"abc\nde", b"f"]),
+            Ok(vec![b"abc".to_vec(), b"def".to_vec()])
+        );
+    }
+
+    #[test]
+    fn line_too_long() {
+        let mut codec = LineCodec::new(4);
+        assert_eq!(
+            decode_chunks(&mut codec, &[b"abcd\n"]),
+            Ok(vec![b"abcd".to_vec()])
+        );
+        assert_eq!(
+            decode_chunks(&mut codec, &[b"abcde\n"]),
+            Err(Error::LineTooLong(4))
+        );
+        // Detected before the newline ever arrives
+        assert_eq!(
+            decode_chunks(&mut codec, &[b"abc", b"de"]),
+            Err(Error::LineTooLong(4))
+        );
+    }
+
+    fn lines() -> impl Strategy<Value = Vec<Vec<u8>>> {
+        proptest::collection::vec(
+            proptest::collection::vec(
+                any::<u8>().prop_filter("no newlines", |b| *b != b'\n'),
+                0..32,
+            ),
+            0..16,
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn arbitrary_chunk_boundaries(lines in lines(), cuts in proptest::collection::vec(any::<prop::sample::Index>(), 0..16)) {
+            let mut stream: Vec<u8> = vec![];
+            for line in lines.iter() {
+                stream.extend_from_slice(line);
+                stream.push(b'\n');
+            }
+            let mut cuts: Vec<usize> = cuts.iter().map(|i| i.index(stream.len() + 1)).collect();
+            cuts.push(0);
+            cuts.push(stream.len());
+            cuts.sort();
+            let chunks: Vec<&[u8]> = cuts.windows(2).map(|w| &stream[w[0]..w[1]]).collect();
+            let mut codec = LineCodec::new(32);
+            prop_assert_eq!(decode_chunks(&mut codec, &chunks), Ok(lines));
+        }
+    }
+}
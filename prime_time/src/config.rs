@@ -0,0 +1,152 @@
+use crate::backend::Backend;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Parser)]
+#[command(about = "Protohackers prime time server")]
+pub struct Config {
+    /// Address to listen on
+    #[arg(long, default_value = "0.0.0.0:1337")]
+    pub listen: String,
+
+    /// Answer bad requests with a JSON error object instead of the bare
+    /// `malformed request`, and keep serving the connection when the error
+    /// only affects that one line
+    #[arg(long)]
+    pub json_errors: bool,
+
+    /// Also take JSON-RPC 2.0 calls and batches on the same connections. Off
+    /// by default, as the plain protocol calls arrays malformed and ignores
+    /// unknown fields such as `jsonrpc`
+    #[arg(long)]
+    pub json_rpc: bool,
+
+    /// Longest request line accepted, in bytes
+    #[arg(long, default_value_t = 1024 * 1024)]
+    pub max_line_length: usize,
+
+    /// Close connections that send nothing for this long while none of their
+    /// requests are outstanding, in seconds; 0 for no limit
+    #[arg(long, default_value_t = 60)]
+    pub idle_timeout_secs: u64,
+
+    /// Time allowed to finish a line once it starts arriving, in seconds; 0
+    /// for no limit
+    #[arg(long, default_value_t = 30)]
+    pub line_timeout_secs: u64,
+
+    /// Longest a connection may stay open, in seconds; 0 for no limit
+    #[arg(long, default_value_t = 3600)]
+    pub max_connection_secs: u64,
+
+    /// On shutdown, how long open connections get to send the responses
+    /// they still owe, in seconds
+    #[arg(long, default_value_t = 10)]
+    pub shutdown_grace_secs: u64,
+
+    /// Requests per connection answered concurrently; responses past this
+    /// many that are still waiting for an earlier one count towards it too
+    #[arg(long, default_value_t = 32)]
+    pub max_in_flight: usize,
+
+    /// How `isPrime` and `isPrimeMany` decide
+    #[arg(long, value_enum, default_value_t = Backend::Auto)]
+    pub primality: Backend,
+
+    /// Numbers below this are looked up in a table of primes sieved at
+    /// startup, which takes a bit per odd number; 0 disables it
+    #[arg(long, default_value_t = 1 << 28)]
+    pub sieve_limit: u64,
+
+    /// Numbers with more bits than this are tested on the compute pool
+    /// instead of inline on the connection's task
+    #[arg(long, default_value_t = 128)]
+    pub offload_bits: u32,
+
+    /// Threads in the compute pool
+    #[arg(long, default_value_t = default_compute_threads())]
+    pub compute_threads: usize,
+
+    /// Jobs that may wait for a compute thread before submission blocks
+    #[arg(long, default_value_t = 256)]
+    pub compute_queue: usize,
+
+    /// Time allowed for a single request, in milliseconds; 0 for no limit
+    #[arg(long, default_value_t = 10_000)]
+    pub request_budget_ms: u64,
+
+    /// Answers kept in the shared result cache; 0 disables caching
+    #[arg(long, default_value_t = 100_000)]
+    pub cache_capacity: usize,
+
+    /// Primes below this are kept in the cache for good once seen, on top
+    /// of `cache_capacity`
+    #[arg(long, default_value_t = 1 << 16)]
+    pub cache_pin_below: u64,
+
+    /// How often to log the cache's counters, in seconds; 0 to never
+    #[arg(long, default_value_t = 60)]
+    pub cache_report_secs: u64,
+}
+
+impl Config {
+    /// Checks the combinations of flags clap can't
+    pub fn validate(&self) -> Result<(), clap::Error> {
+        if self.primality == Backend::Sieve && self.sieve_limit == 0 {
+            return Err(Self::command().error(
+                ErrorKind::ArgumentConflict,
+                "--primality sieve needs a sieve; set --sieve-limit above 0",
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn request_budget(&self) -> Option<Duration> {
+        (self.request_budget_ms > 0).then(|| Duration::from_millis(self.request_budget_ms))
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        limit(self.idle_timeout_secs)
+    }
+
+    pub fn line_timeout(&self) -> Option<Duration> {
+        limit(self.line_timeout_secs)
+    }
+
+    pub fn max_connection(&self) -> Option<Duration> {
+        limit(self.max_connection_secs)
+    }
+
+    pub fn shutdown_grace(&self) -> Duration {
+        Duration::from_secs(self.shutdown_grace_secs)
+    }
+}
+
+/// Zero means "no limit" for every timeout
+fn limit(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+fn default_compute_threads() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(args: &[&str]) -> Config {
+        Config::parse_from(std::iter::once("prime_time").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn sieve_backend_needs_a_sieve() {
+        assert!(config(&["--primality", "sieve"]).validate().is_ok());
+        assert!(config(&["--sieve-limit", "0"]).validate().is_ok());
+        let error = config(&["--primality", "sieve", "--sieve-limit", "0"])
+            .validate()
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ArgumentConflict);
+    }
+}
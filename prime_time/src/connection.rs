@@ -0,0 +1,748 @@
+use crate::codec::{self, LineCodec};
+use crate::config::Config;
+use crate::verif::{self, Engine, ErrorResponse};
+use anyhow::Result;
+use bytes::BytesMut;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::select;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, mpsc, oneshot};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info};
+
+/// An answer together with its place in the window of in-flight requests;
+/// the permit is only released once the answer has been written.
+type Answer = (Result<Vec<u8>, verif::Error>, OwnedSemaphorePermit);
+
+/// Serves one client until it hangs up or sends something malformed, or,
+/// with `json_errors`, something malformed past the point of recovery.
+/// Hanging up includes closing just the sending side: nothing tells that apart
+/// from a client that has gone, so requests still in flight are abandoned
+/// either way. Once `shutdown` is cancelled no more requests are read, but
+/// those already read are still answered.
+///
+/// Up to `max_in_flight` requests are answered concurrently. Every request
+/// gets a slot in a queue at the moment it is read, and the writer drains
+/// that queue front to back, so a response can only go out once every
+/// earlier response has.
+pub async fn serve<R, W>(
+    reader: R,
+    mut writer: W,
+    engine: Arc<Engine>,
+    config: &Config,
+    shutdown: CancellationToken,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin,
+{
+    // Cancelled when the peer hangs up or resets the connection, or when we
+    // stop serving it, any of which abandons in-flight computation
+    let token = CancellationToken::new();
+    let _cancel = token.clone().drop_guard();
+    // Requests read but not yet answered; a connection waiting on one of
+    // those isn't idle
+    let pending = Arc::new(AtomicUsize::new(0));
+    let (tx, lines) = mpsc::channel(1);
+    tokio::spawn(read_lines(
+        SocketReader::new(reader, config.max_line_length, Limits::new(config)),
+        tx,
+        pending.clone(),
+        token.clone(),
+        shutdown,
+    ));
+    let (slots_tx, mut slots) = mpsc::unbounded_channel();
+    let window = Arc::new(Semaphore::new(config.max_in_flight.max(1)));
+    let dispatcher = tokio::spawn(dispatch(lines, slots_tx, engine, window, token.clone()));
+
+    // Once cancelled, whatever is still in flight is abandoned unanswered
+    loop {
+        let (line, slot) = select! {
+            biased;
+            _ = token.cancelled() => break,
+            slot = slots.recv() => match slot {
+                Some(slot) => slot,
+                None => break,
+            },
+        };
+        let answer = select! {
+            biased;
+            _ = token.cancelled() => break,
+            answer = slot => answer,
+        };
+        let Ok((answer, _permit)) = answer else {
+            // The request's task was torn down, which only happens once the
+            // connection is being cancelled anyway
+            break;
+        };
+        pending.fetch_sub(1, Ordering::Relaxed);
+        match answer {
+            Ok(buf) => writer.write_all(&buf).await?,
+            Err(e) => {
+                error!("Error process request on line {line}: {e}");
+                if !config.json_errors {
+                    writer.write_all(b"malformed request").await?;
+                    break;
+                }
+                let buf: Vec<u8> = ErrorResponse::new(&e, line).serialize().collect();
+                writer.write_all(&buf).await?;
+                if !e.is_recoverable() {
+                    break;
+                }
+            }
+        }
+    }
+    token.cancel();
+    let _ = dispatcher.await;
+    Ok(())
+}
+
+/// Starts answering each line as it arrives, queueing a slot for the answer
+async fn dispatch(
+    mut lines: mpsc::Receiver<Result<BytesMut, codec::Error>>,
+    slots: mpsc::UnboundedSender<(usize, oneshot::Receiver<Answer>)>,
+    engine: Arc<Engine>,
+    window: Arc<Semaphore>,
+    token: CancellationToken,
+) {
+    for number in 1.. {
+        let permit = select! {
+            permit = window.clone().acquire_owned() => permit.expect("Window is never closed"),
+            _ = token.cancelled() => break,
+        };
+        let line = select! {
+            line = lines.recv() => line,
+            _ = token.cancelled() => break,
+        };
+        let Some(line) = line else { break };
+        let (tx, rx) = oneshot::channel();
+        if slots.send((number, rx)).is_err() {
+            break;
+        }
+        match line {
+            Ok(line) => {
+                debug!("Read line: {}", String::from_utf8_lossy(&line));
+                let engine = engine.clone();
+                let token = token.clone();
+                tokio::spawn(async move {
+                    let answer = engine.answer(&line, &token).await;
+                    let _ = tx.send((answer, permit));
+                });
+            }
+            Err(e) => {
+                // Nothing after an unreadable line can be trusted
+                let _ = tx.send((Err(e.into()), permit));
+                break;
+            }
+        }
+    }
+}
+
+/// Feeds lines to the connection task. Reading carries on while requests are
+/// being answered, which is how a reset connection gets noticed.
+async fn read_lines<R: AsyncRead + Unpin>(
+    mut socket: SocketReader<R>,
+    tx: mpsc::Sender<Result<BytesMut, codec::Error>>,
+    pending: Arc<AtomicUsize>,
+    token: CancellationToken,
+    shutdown: CancellationToken,
+) {
+    loop {
+        let line = select! {
+            line = socket.read_line() => line,
+            _ = token.cancelled() => break,
+            _ = shutdown.cancelled() => {
+                info!("Server shutting down, no longer reading requests");
+                break;
+            }
+        };
+        let line = match line {
+            Ok(Some(line)) => Ok(line),
+            Ok(None) => {
+                info!("Client hung up");
+                token.cancel();
+                break;
+            }
+            Err(ReadError::Codec(e)) => Err(e),
+            Err(ReadError::TimedOut(Limit::Idle)) if pending.load(Ordering::Relaxed) > 0 => {
+                continue;
+            }
+            Err(ReadError::TimedOut(limit)) => {
+                info!("Closing connection: {limit} reached");
+                token.cancel();
+                break;
+            }
+            Err(ReadError::Io(e)) => {
+                info!("Connection lost: {e}");
+                token.cancel();
+                break;
+            }
+        };
+        pending.fetch_add(1, Ordering::Relaxed);
+        if tx.send(line).await.is_err() {
+            break;
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+enum ReadError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Codec(#[from] codec::Error),
+    #[error("{0} reached")]
+    TimedOut(Limit),
+}
+
+/// The timeouts that can close a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+enum Limit {
+    #[error("idle timeout")]
+    Idle,
+    #[error("partial line timeout")]
+    PartialLine,
+    #[error("maximum connection lifetime")]
+    Lifetime,
+}
+
+struct Limits {
+    idle: Option<Duration>,
+    partial_line: Option<Duration>,
+    closes_at: Option<Instant>,
+}
+
+impl Limits {
+    fn new(config: &Config) -> Self {
+        Self {
+            idle: config.idle_timeout(),
+            partial_line: config.line_timeout(),
+            closes_at: config.max_connection().map(|d| Instant::now() + d),
+        }
+    }
+}
+
+struct SocketReader<R> {
+    socket: R,
+    buffer: BytesMut,
+    codec: LineCodec,
+    limits: Limits,
+    // When the oldest unfinished line in `buffer` started arriving
+    partial_since: Option<Instant>,
+}
+
+impl<R: AsyncRead + Unpin> SocketReader<R> {
+    pub fn new(socket: R, max_line_length: usize, limits: Limits) -> Self {
+        Self {
+            socket,
+            buffer: BytesMut::with_capacity(1024),
+            codec: LineCodec::new(max_line_length),
+            limits,
+            partial_since: None,
+        }
+    }
+
+    /// Returns the next complete line, or `None` once the peer has hung up
+    pub async fn read_line(&mut self) -> Result<Option<BytesMut>, ReadError> {
+        loop {
+            if let Some(line) = self.codec.decode(&mut self.buffer)? {
+                self.partial_since = None;
+                return Ok(Some(line));
+            }
+            if !self.buffer.is_empty() {
+                self.partial_since.get_or_insert_with(Instant::now);
+            }
+            let read = match self.deadline() {
+                Some((deadline, limit)) => {
+                    tokio::time::timeout_at(deadline, self.socket.read_buf(&mut self.buffer))
+                        .await
+                        .map_err(|_| ReadError::TimedOut(limit))??
+                }
+                None => self.socket.read_buf(&mut self.buffer).await?,
+            };
+            if read == 0 {
+                return Ok(self.codec.decode_eof(&mut self.buffer)?);
+            }
+        }
+    }
+
+    /// The first limit this read could run into
+    fn deadline(&self) -> Option<(Instant, Limit)> {
+        let waiting = match self.partial_since {
+            Some(since) => self
+                .limits
+                .partial_line
+                .map(|d| (since + d, Limit::PartialLine)),
+            None => self.limits.idle.map(|d| (Instant::now() + d, Limit::Idle)),
+        };
+        let lifetime = self.limits.closes_at.map(|at| (at, Limit::Lifetime));
+        waiting
+            .into_iter()
+            .chain(lifetime)
+            .min_by_key(|(at, _)| *at)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use clap::Parser;
+    use proptest::prelude::*;
+    use rug::Integer;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    fn config(args: &[&str]) -> Config {
+        Config::parse_from(std::iter::once("prime_time").chain(args.iter().copied()))
+    }
+
+    /// Sends `input` in one go and collects what the server writes until it
+    /// closes the connection or has answered every line, then hangs up
+    async fn exchange(input: Vec<u8>, config: Config) -> Vec<u8> {
+        let lines = input
+            .split(|&b| b == b'\n')
+            .filter(|l| !l.is_empty())
+            .count();
+        exchange_expecting(input, config, lines).await
+    }
+
+    /// `exchange`, for when `answers` lines come back rather than one a line
+    async fn exchange_expecting(input: Vec<u8>, config: Config, answers: usize) -> Vec<u8> {
+        let engine = Arc::new(Engine::new(&config, None));
+        let (client, server) = tokio::io::duplex(1 << 20);
+        let (server_read, server_write) = tokio::io::split(server);
+        let server = tokio::spawn(async move {
+            serve(
+                server_read,
+                server_write,
+                engine,
+                &config,
+                CancellationToken::new(),
+            )
+            .await
+            .unwrap();
+        });
+        let (client_read, mut client_write) = tokio::io::split(client);
+        client_write.write_all(&input).await.unwrap();
+        // Hanging up any earlier would abandon the requests still in flight
+        let mut client_read = BufReader::new(client_read);
+        let mut output = vec![];
+        for _ in 0..answers {
+            if client_read.read_until(b'\n', &mut output).await.unwrap() == 0 {
+                break;
+            }
+        }
+        client_write.shutdown().await.unwrap();
+        client_read.read_to_end(&mut output).await.unwrap();
+        server.await.unwrap();
+        output
+    }
+
+    fn request(n: &Integer) -> String {
+        format!("{{\"method\":\"isPrime\",\"number\":{n}}}\n")
+    }
+
+    fn response(prime: bool) -> String {
+        format!("{{\"method\":\"isPrime\",\"prime\":{prime}}}\n")
+    }
+
+    /// `exchange` for proptests, which can't be async
+    fn exchange_blocking(input: String, config: Config) -> String {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        String::from_utf8(runtime.block_on(exchange(input.into_bytes(), config))).unwrap()
+    }
+
+    fn numbers() -> impl Strategy<Value = Vec<i64>> {
+        proptest::collection::vec(-1000i64..=10000i64, 0..40)
+    }
+
+    fn gibberish() -> impl Strategy<Value = String> {
+        proptest::string::string_regex("a-zA-Z").unwrap()
+    }
+
+    /// Requests for `numbers`, and what they should get back
+    fn script(numbers: &[i64]) -> (String, String) {
+        let input = numbers.iter().map(|&n| request(&n.into())).collect();
+        let expected = numbers
+            .iter()
+            .map(|&n| response(u64::try_from(n).is_ok_and(crate::primality::is_prime_u64)))
+            .collect();
+        (input, expected)
+    }
+
+    // Alternates 2^p - 1, for exponents big enough to keep the pool busy, with
+    // small numbers that are answered inline
+    fn mixed_workload() -> (String, String) {
+        let mersenne = [
+            (2203, true),
+            (2281, true),
+            (2003, false),
+            (2, true),
+            (3217, true),
+            (4423, true),
+            (2000, false),
+            (1279, true),
+        ];
+        let mut input = String::new();
+        let mut expected = String::new();
+        for (i, (p, prime)) in mersenne.into_iter().enumerate() {
+            input += &request(&((Integer::from(1) << p) - 1));
+            expected += &response(prime);
+            let small = i as u64 * 7 + 1;
+            input += &request(&Integer::from(small));
+            expected += &response(crate::primality::is_prime_u64(small));
+        }
+        (input, expected)
+    }
+
+    #[tokio::test]
+    async fn responses_in_request_order() {
+        let (input, expected) = mixed_workload();
+        for in_flight in ["1", "2", "16"] {
+            let config = config(&["--offload-bits", "64", "--max-in-flight", in_flight]);
+            let output = exchange(input.clone().into_bytes(), config).await;
+            assert_eq!(String::from_utf8(output).unwrap(), expected, "{in_flight}");
+        }
+    }
+
+    #[tokio::test]
+    async fn malformed_after_pipelined_requests() {
+        let (mut input, mut expected) = mixed_workload();
+        input += "{\"method\":\"isPrime\"}\n";
+        input += &request(&Integer::from(7));
+        expected += "malformed request";
+        let config = config(&["--offload-bits", "64", "--max-in-flight", "8"]);
+        let output = exchange(input.into_bytes(), config).await;
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn line_too_long() {
+        let mut input = request(&Integer::from(7));
+        input += &"1".repeat(100);
+        let config = config(&["--max-line-length", "64"]);
+        let output = exchange(input.into_bytes(), config).await;
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            response(true) + "malformed request"
+        );
+    }
+
+    #[tokio::test]
+    async fn json_errors_keep_the_connection() {
+        let input = [
+            request(&Integer::from(7)),
+            "{\"method\":\"isPrime\"}\n".to_string(),
+            "not json\n".to_string(),
+            "{\"method\":\"factorize\",\"number\":0.5}\n".to_string(),
+            request(&Integer::from(8)),
+        ];
+        let config = config(&["--json-errors"]);
+        let output = exchange(input.concat().into_bytes(), config).await;
+        let lines: Vec<serde_json::Value> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0]["prime"], true);
+        for (line, code) in [
+            (2, "missing_number"),
+            (3, "invalid_json"),
+            (4, "not_an_integer"),
+        ] {
+            let error = &lines[line - 1]["error"];
+            assert_eq!(error["code"], code);
+            assert_eq!(error["line"], line);
+            assert!(error["message"].is_string());
+        }
+        assert_eq!(lines[4]["prime"], false);
+    }
+
+    #[tokio::test]
+    async fn json_rpc_alongside_plain_requests() {
+        let input = [
+            request(&Integer::from(7)),
+            "{\"jsonrpc\":\"2.0\",\"method\":\"isPrime\",\"params\":{\"number\":7}}\n".to_string(),
+            "{\"jsonrpc\":\"2.0\",\"id\":3,\"method\":\"isPrime\",\"params\":{\"number\":7}}\n"
+                .to_string(),
+            request(&Integer::from(8)),
+        ];
+        // The notification gets no line at all
+        let config = config(&["--json-rpc"]);
+        let output = exchange_expecting(input.concat().into_bytes(), config, 3).await;
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            response(true)
+                + "{\"jsonrpc\":\"2.0\",\"id\":3,\"result\":{\"prime\":true}}\n"
+                + &response(false)
+        );
+    }
+
+    #[tokio::test]
+    async fn plain_protocol_by_default() {
+        let extra = "{\"method\":\"isPrime\",\"number\":7,\"jsonrpc\":\"2.0\"}\n";
+        for batch in ["[]\n", "[1,2]\n"] {
+            let input = [extra, batch, &request(&Integer::from(7))].concat();
+            let output = exchange(input.into_bytes(), config(&[])).await;
+            assert_eq!(
+                String::from_utf8(output).unwrap(),
+                response(true) + "malformed request",
+                "{batch}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn huge_numbers_in_the_plain_protocol() {
+        // isPrime can still say no, but nothing else can answer
+        let input = [
+            "{\"method\":\"isPrime\",\"number\":1e100000}\n",
+            "{\"method\":\"nextPrime\",\"number\":1e100000}\n",
+            &request(&Integer::from(7)),
+        ]
+        .concat();
+        let output = exchange(input.into_bytes(), config(&[])).await;
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            response(false) + "malformed request"
+        );
+    }
+
+    #[tokio::test]
+    async fn timeouts_without_json_errors() {
+        // The plain protocol has no way to say "gave up", so it's malformed
+        let slow = request(&((Integer::from(1) << 9941u32) - 1));
+        let input = [request(&Integer::from(7)), slow, request(&Integer::from(7))].concat();
+        let args = ["--offload-bits", "64", "--request-budget-ms", "1"];
+        for extra in [None, Some("--json-rpc")] {
+            let args: Vec<_> = args.into_iter().chain(extra).collect();
+            let output = exchange(input.clone().into_bytes(), config(&args)).await;
+            assert_eq!(
+                String::from_utf8(output).unwrap(),
+                response(true) + "malformed request",
+                "{extra:?}"
+            );
+        }
+        let args: Vec<_> = args.into_iter().chain(["--json-errors"]).collect();
+        let output = String::from_utf8(exchange(input.into_bytes(), config(&args)).await).unwrap();
+        let lines: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1]["error"]["code"], "timeout");
+        assert_eq!(lines[2]["prime"], true);
+    }
+
+    #[tokio::test]
+    async fn json_errors_close_on_bad_framing() {
+        let input =
+            request(&Integer::from(7)) + &"1".repeat(100) + "\n" + &request(&Integer::from(7));
+        let config = config(&["--json-errors", "--max-line-length", "64"]);
+        let output = exchange(input.into_bytes(), config).await;
+        let output = String::from_utf8(output).unwrap();
+        let (first, rest) = output.split_once('\n').unwrap();
+        assert_eq!(format!("{first}\n"), response(true));
+        let error: serde_json::Value = serde_json::from_str(rest.trim_end()).unwrap();
+        assert_eq!(error["error"]["code"], "line_too_long");
+        assert_eq!(error["error"]["line"], 2);
+    }
+
+    #[tokio::test]
+    async fn responses_stream_before_input_ends() {
+        let config = config(&[]);
+        let engine = Arc::new(Engine::new(&config, None));
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let (server_read, server_write) = tokio::io::split(server);
+        tokio::spawn(async move {
+            serve(
+                server_read,
+                server_write,
+                engine,
+                &config,
+                CancellationToken::new(),
+            )
+            .await
+        });
+        let (client_read, mut client_write) = tokio::io::split(client);
+        let mut client_read = BufReader::new(client_read);
+        for n in [2, 4, 97] {
+            client_write
+                .write_all(request(&Integer::from(n)).as_bytes())
+                .await
+                .unwrap();
+            let mut line = String::new();
+            client_read.read_line(&mut line).await.unwrap();
+            assert_eq!(line, response(n != 4));
+        }
+    }
+
+    #[tokio::test]
+    async fn hanging_up_abandons_work_in_flight() {
+        // One compute thread, and no time limit to stop a request for us
+        let config = config(&[
+            "--compute-threads",
+            "1",
+            "--offload-bits",
+            "64",
+            "--request-budget-ms",
+            "0",
+        ]);
+        let engine = Arc::new(Engine::new(&config, None));
+        let connect = |config: Config| {
+            let engine = engine.clone();
+            let (client, server) = tokio::io::duplex(1 << 16);
+            let (server_read, server_write) = tokio::io::split(server);
+            let token = CancellationToken::new();
+            let server = tokio::spawn(async move {
+                serve(server_read, server_write, engine, &config, token).await
+            });
+            (client, server)
+        };
+
+        // Two primes rho would take ages to pull apart
+        let (mut gone, server) = connect(config.clone());
+        let p = (Integer::from(1) << 89u32) - 1;
+        let q = (Integer::from(1) << 107u32) - 1;
+        let line = format!("{{\"method\":\"factorize\",\"number\":{}}}\n", p * q);
+        gone.write_all(line.as_bytes()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        gone.shutdown().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("Still serving a client that hung up")
+            .unwrap()
+            .unwrap();
+
+        // The compute thread is free again for the next client
+        let (client, _server) = connect(config);
+        let (client_read, mut client_write) = tokio::io::split(client);
+        let mersenne = (Integer::from(1) << 127u32) - 1;
+        client_write
+            .write_all(request(&mersenne).as_bytes())
+            .await
+            .unwrap();
+        let mut line = String::new();
+        let mut client_read = BufReader::new(client_read);
+        tokio::time::timeout(Duration::from_secs(5), client_read.read_line(&mut line))
+            .await
+            .expect("Still factoring for a client that hung up")
+            .unwrap();
+        assert_eq!(line, response(true));
+    }
+
+    #[tokio::test]
+    async fn shutdown_finishes_in_flight_requests() {
+        let config = config(&["--offload-bits", "64"]);
+        let engine = Arc::new(Engine::new(&config, None));
+        let shutdown = CancellationToken::new();
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let (server_read, server_write) = tokio::io::split(server);
+        let server = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { serve(server_read, server_write, engine, &config, shutdown).await }
+        });
+        let (client_read, mut client_write) = tokio::io::split(client);
+        let mut client_read = BufReader::new(client_read);
+        // A slow request behind a quick one, and half of a third that never
+        // gets finished
+        let slow = (Integer::from(1) << 4423) - 1;
+        let input = request(&Integer::from(2)) + &request(&slow) + "{\"method\":";
+        client_write.write_all(input.as_bytes()).await.unwrap();
+        let mut line = String::new();
+        client_read.read_line(&mut line).await.unwrap();
+        assert_eq!(line, response(true));
+
+        shutdown.cancel();
+        let mut rest = String::new();
+        client_read.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, response(true));
+        server.await.unwrap().unwrap();
+    }
+
+    /// Sends `pieces` with `pause` after each, never hanging up, and returns
+    /// what the server wrote and how long it took to close the connection
+    async fn trickle(pieces: Vec<String>, pause: Duration, config: Config) -> (String, Duration) {
+        let engine = Arc::new(Engine::new(&config, None));
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let (server_read, server_write) = tokio::io::split(server);
+        tokio::spawn(async move {
+            serve(
+                server_read,
+                server_write,
+                engine,
+                &config,
+                CancellationToken::new(),
+            )
+            .await
+        });
+        let (mut client_read, mut client_write) = tokio::io::split(client);
+        let start = Instant::now();
+        let sender = tokio::spawn(async move {
+            for piece in pieces {
+                client_write.write_all(piece.as_bytes()).await.unwrap();
+                tokio::time::sleep(pause).await;
+            }
+            std::future::pending::<()>().await;
+        });
+        let mut output = String::new();
+        client_read.read_to_string(&mut output).await.unwrap();
+        sender.abort();
+        (output, start.elapsed())
+    }
+
+    fn requests(n: usize) -> Vec<String> {
+        (0..n).map(|_| request(&Integer::from(7))).collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_timeout() {
+        let idle = || config(&["--idle-timeout-secs", "5"]);
+        let (output, elapsed) = trickle(vec![], Duration::ZERO, idle()).await;
+        assert_eq!(output, "");
+        assert_eq!(elapsed.as_secs(), 5);
+
+        // Every request starts the clock again
+        let (output, elapsed) = trickle(requests(3), Duration::from_secs(4), idle()).await;
+        assert_eq!(output, response(true).repeat(3));
+        assert_eq!(elapsed.as_secs(), 13);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn partial_line_timeout() {
+        let pieces = ["{\"method\":", "\"isPrime\",", "\"number\":7"];
+        let pieces = pieces.map(String::from).to_vec();
+        let config = config(&["--idle-timeout-secs", "60", "--line-timeout-secs", "15"]);
+        let (output, elapsed) = trickle(pieces, Duration::from_secs(10), config).await;
+        assert_eq!(output, "");
+        assert_eq!(elapsed.as_secs(), 15);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connection_lifetime() {
+        let config = config(&["--max-connection-secs", "25"]);
+        let (output, elapsed) = trickle(requests(5), Duration::from_secs(10), config).await;
+        assert_eq!(output, response(true).repeat(3));
+        assert_eq!(elapsed.as_secs(), 25);
+    }
+
+    proptest! {
+        #[test]
+        fn every_request_processed(numbers in numbers()) {
+            let (input, expected) = script(&numbers);
+            prop_assert_eq!(exchange_blocking(input, config(&[])), expected);
+        }
+
+        #[test]
+        fn up_to_malformed(before in numbers(), gibberish in gibberish(), after in numbers()) {
+            let (mut input, mut expected) = script(&before);
+            input += &(gibberish + "\n");
+            input += &script(&after).0;
+            expected += "malformed request";
+            prop_assert_eq!(exchange_blocking(input, config(&[])), expected);
+        }
+    }
+}
@@ -0,0 +1,299 @@
+use crate::budget::{Budget, Interrupted};
+use std::sync::OnceLock;
+
+/// The largest `x` that [`prime_count`] takes
+pub const MAX_COUNT: u64 = 1_000_000_000_000;
+
+/// The largest `n` that [`nth_prime`] takes, `π(MAX_COUNT)`
+pub const MAX_NTH: u64 = 37_607_912_018;
+
+// Counts up to here come straight out of a sieve. It has to reach the square
+// root of anything we segment-sieve, so well past sqrt(MAX_COUNT).
+const SIEVE_LIMIT: u64 = 1 << 24;
+
+// phi(x, a) for a up to this many primes repeats with period 2*3*5*7*11*13
+const WHEEL_PRIMES: u64 = 6;
+const WHEEL: u64 = 30030;
+
+const WINDOW: u64 = 1 << 20;
+
+/// The number of primes `<= x`, by Lehmer's formula
+pub fn prime_count(x: u64, budget: &Budget) -> Result<u64, Interrupted> {
+    tables().count(x, budget)
+}
+
+/// The `n`th prime, counting 2 as the first. `n` must be at least 1.
+pub fn nth_prime(n: u64, budget: &Budget) -> Result<u64, Interrupted> {
+    assert!(n >= 1, "There is no 0th prime");
+    let tables = tables();
+    if let Some(&p) = tables.primes.get(n as usize - 1) {
+        return Ok(p as u64);
+    }
+    // Count up to an estimate, then sieve from there to the prime itself
+    let estimate = cipolla(n);
+    let below = tables.count(estimate, budget)?;
+    if below >= n {
+        // The prime we want is the (below - n)th one down from the estimate
+        let mut skip = below - n;
+        let mut hi = estimate + 1;
+        loop {
+            budget.check()?;
+            let lo = hi.saturating_sub(WINDOW);
+            let primes = tables.primes_in(lo, hi);
+            if let Some(i) = primes.len().checked_sub(skip as usize + 1) {
+                return Ok(primes[i]);
+            }
+            skip -= primes.len() as u64;
+            hi = lo;
+        }
+    } else {
+        let mut skip = n - below - 1;
+        let mut lo = estimate + 1;
+        loop {
+            budget.check()?;
+            let primes = tables.primes_in(lo, lo + WINDOW);
+            if let Some(&p) = primes.get(skip as usize) {
+                return Ok(p);
+            }
+            skip -= primes.len() as u64;
+            lo += WINDOW;
+        }
+    }
+}
+
+// Cipolla's asymptotic for the nth prime, close enough that the sieving
+// after it is cheap next to the count
+fn cipolla(n: u64) -> u64 {
+    let n = n as f64;
+    let ln = n.ln();
+    let lnln = ln.ln();
+    (n * (ln + lnln - 1.0 + (lnln - 2.0) / ln)) as u64
+}
+
+fn tables() -> &'static Tables {
+    static TABLES: OnceLock<Tables> = OnceLock::new();
+    TABLES.get_or_init(Tables::new)
+}
+
+struct Tables {
+    // Bit `i % 64` of word `i / 64` says whether `i` is prime
+    bits: Vec<u64>,
+    // The number of primes below `64 * i`
+    counts: Vec<u32>,
+    primes: Vec<u32>,
+    // phi(m, a) for every a up to WHEEL_PRIMES and m up to WHEEL
+    wheel: Vec<Vec<u16>>,
+}
+
+impl Tables {
+    fn new() -> Self {
+        let composite = sieve(SIEVE_LIMIT as usize + 1);
+        let mut bits = vec![0u64; composite.len().div_ceil(64)];
+        let mut primes = vec![];
+        for (i, &composite) in composite.iter().enumerate() {
+            if !composite {
+                bits[i / 64] |= 1 << (i % 64);
+                primes.push(i as u32);
+            }
+        }
+        let mut counts = Vec::with_capacity(bits.len());
+        let mut total = 0;
+        for word in &bits {
+            counts.push(total);
+            total += word.count_ones();
+        }
+        let wheel = (0..=WHEEL_PRIMES as usize)
+            .map(|a| {
+                let mut phi = 0;
+                (0..=WHEEL)
+                    .map(|m| {
+                        if m > 0 && primes[..a].iter().all(|&p| m % p as u64 != 0) {
+                            phi += 1;
+                        }
+                        phi
+                    })
+                    .collect()
+            })
+            .collect();
+        Self {
+            bits,
+            counts,
+            primes,
+            wheel,
+        }
+    }
+
+    /// The `i`th prime, counting from 1
+    fn prime(&self, i: u64) -> u64 {
+        self.primes[i as usize - 1] as u64
+    }
+
+    /// `π(x)` for `x <= SIEVE_LIMIT`
+    fn pi(&self, x: u64) -> u64 {
+        let word = (x / 64) as usize;
+        let mask = u64::MAX >> (63 - x % 64);
+        self.counts[word] as u64 + (self.bits[word] & mask).count_ones() as u64
+    }
+
+    fn count(&self, x: u64, budget: &Budget) -> Result<u64, Interrupted> {
+        if x <= SIEVE_LIMIT {
+            return Ok(self.pi(x));
+        }
+        let a = self.pi(iroot(x, 4));
+        let b = self.pi(iroot(x, 2));
+        let c = self.pi(iroot(x, 3));
+        let mut sum = self.phi(x, a, budget)? + (b + a - 2) * (b - a + 1) / 2;
+        for i in a + 1..=b {
+            budget.check()?;
+            let w = x / self.prime(i);
+            sum -= self.count(w, budget)?;
+            if i <= c {
+                for j in i..=self.pi(iroot(w, 2)) {
+                    sum -= self.count(w / self.prime(j), budget)? - (j - 1);
+                }
+            }
+        }
+        Ok(sum)
+    }
+
+    /// The number of integers in `1..=x` with none of the first `a` primes
+    /// as a factor
+    fn phi(&self, x: u64, a: u64, budget: &Budget) -> Result<u64, Interrupted> {
+        if a <= WHEEL_PRIMES {
+            return Ok(self.phi_wheel(x, a));
+        }
+        if x < self.prime(a) {
+            return Ok(x.min(1));
+        }
+        if x <= SIEVE_LIMIT && self.prime(a).pow(2) > x {
+            return Ok(self.pi(x) - a + 1);
+        }
+        // phi(x, a) = phi(x, a - 1) - phi(x / p_a, a - 1), unrolled down to
+        // the wheel
+        let mut sum = self.phi_wheel(x, WHEEL_PRIMES);
+        for i in WHEEL_PRIMES + 1..=a {
+            budget.check()?;
+            sum -= self.phi(x / self.prime(i), i - 1, budget)?;
+        }
+        Ok(sum)
+    }
+
+    fn phi_wheel(&self, x: u64, a: u64) -> u64 {
+        let wheel = &self.wheel[a as usize];
+        (x / WHEEL) * wheel[WHEEL as usize] as u64 + wheel[(x % WHEEL) as usize] as u64
+    }
+
+    /// The primes in `lo..hi`, where `hi <= SIEVE_LIMIT^2`
+    fn primes_in(&self, lo: u64, hi: u64) -> Vec<u64> {
+        let mut composite = vec![false; (hi - lo) as usize];
+        for &p in &self.primes {
+            let p = p as u64;
+            if p * p >= hi {
+                break;
+            }
+            let first = lo.div_ceil(p).max(p) * p;
+            for m in (first..hi).step_by(p as usize) {
+                composite[(m - lo) as usize] = true;
+            }
+        }
+        (lo.max(2)..hi)
+            .filter(|&n| !composite[(n - lo) as usize])
+            .collect()
+    }
+}
+
+/// `composite[i]` for every `i < limit`, counting 0 and 1 as composite
+fn sieve(limit: usize) -> Vec<bool> {
+    let mut composite = vec![false; limit];
+    composite[..2.min(limit)].fill(true);
+    let mut i = 2;
+    while i * i < limit {
+        if !composite[i] {
+            for j in (i * i..limit).step_by(i) {
+                composite[j] = true;
+            }
+        }
+        i += 1;
+    }
+    composite
+}
+
+/// The integer `k`th root of `x`, rounded down
+fn iroot(x: u64, k: u32) -> u64 {
+    let mut r = (x as f64).powf(1.0 / k as f64) as u64;
+    while r.checked_pow(k).is_none_or(|p| p > x) {
+        r -= 1;
+    }
+    while (r + 1).checked_pow(k).is_some_and(|p| p <= x) {
+        r += 1;
+    }
+    r
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util;
+
+    fn count(x: u64) -> u64 {
+        prime_count(x, &Budget::unlimited()).unwrap()
+    }
+
+    fn nth(n: u64) -> u64 {
+        nth_prime(n, &Budget::unlimited()).unwrap()
+    }
+
+    #[test]
+    fn roots() {
+        assert_eq!(iroot(u64::MAX, 2), u32::MAX as u64);
+        assert_eq!(iroot(999_999_999_999, 3), 9999);
+        assert_eq!(iroot(1_000_000_000_000, 3), 10_000);
+        assert_eq!(iroot(1_000_000_000_000, 4), 1000);
+    }
+
+    #[test]
+    fn small_counts_match_a_sieve() {
+        let mut pi = 0;
+        for (x, prime) in test_util::sieve(100_000).into_iter().enumerate() {
+            pi += prime as u64;
+            assert_eq!(count(x as u64), pi, "{x}");
+        }
+    }
+
+    #[test]
+    fn known_counts() {
+        assert_eq!(count(SIEVE_LIMIT), 1_077_871);
+        assert_eq!(count(SIEVE_LIMIT + 1), 1_077_871);
+        assert_eq!(count(100_000_000), 5_761_455);
+        assert_eq!(count(1_000_000_000), 50_847_534);
+        assert_eq!(count(10_000_000_000), 455_052_511);
+    }
+
+    #[test]
+    fn known_nth_primes() {
+        assert_eq!(nth(1), 2);
+        assert_eq!(nth(1_000_000), 15_485_863);
+        assert_eq!(nth(10_000_000), 179_424_673);
+        assert_eq!(nth(100_000_000), 2_038_074_743);
+        // Just past the sieve, where the estimate is at its worst
+        assert_eq!(nth(1_077_872), 16_777_259);
+    }
+
+    #[test]
+    fn windows_match_the_sieve() {
+        let tables = tables();
+        let expected: Vec<u64> = (0..100_000)
+            .filter(|&n| tables.pi(n) > tables.pi(n.saturating_sub(1)))
+            .collect();
+        assert_eq!(tables.primes_in(0, 100_000), expected);
+        assert_eq!(tables.primes_in(99_990, 100_010), vec![99_991, 100_003]);
+    }
+
+    #[test]
+    fn respects_budget() {
+        let budget = Budget::unlimited();
+        budget.cancel();
+        assert_eq!(prime_count(MAX_COUNT, &budget), Err(Interrupted::Cancelled));
+        assert_eq!(nth_prime(MAX_NTH, &budget), Err(Interrupted::Cancelled));
+    }
+}
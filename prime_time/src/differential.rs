@@ -0,0 +1,127 @@
+//! Checks what `isPrime` answers, all the way from the request line, against
+//! references that share none of its code: GMP's Miller-Rabin and a sieve.
+
+use crate::backend::{Backend, PrimalityTest};
+use crate::budget::Budget;
+use crate::primality::VERIFIED_BELOW;
+use crate::sieve::Sieve;
+use crate::test_util::sieve;
+use crate::verif::Request;
+use proptest::prelude::*;
+use rug::Integer;
+use rug::integer::IsPrime;
+use std::fmt::Display;
+use std::sync::{Arc, LazyLock};
+
+// Enough rounds that GMP calling a composite prime is out of the question
+const REPS: u32 = 50;
+
+// Halfway through `dense_small_range`, so it covers the lookup, the edge of
+// the sieve and what comes after
+const SIEVE_LIMIT: u64 = 100_000;
+
+static PRIMALITY: LazyLock<Arc<dyn PrimalityTest>> =
+    LazyLock::new(|| Backend::Auto.build(Some(Arc::new(Sieve::new(SIEVE_LIMIT)))));
+
+fn answer(n: impl Display) -> bool {
+    let line = format!(r#"{{"method":"isPrime","number":{n}}}"#);
+    let request = Request::parse(line.as_bytes()).unwrap();
+    let response = request.process(&**PRIMALITY, &Budget::unlimited()).unwrap();
+    response.prime().unwrap()
+}
+
+fn reference(n: &Integer) -> bool {
+    n.is_probably_prime(REPS) != IsPrime::No
+}
+
+/// Everything within `radius` of `center`
+fn around(center: &Integer, radius: u32) -> impl Iterator<Item = Integer> {
+    let lo = Integer::from(center - radius);
+    (0..2 * radius).map(move |i| Integer::from(&lo + i))
+}
+
+#[test]
+fn dense_small_range() {
+    for (n, expected) in sieve(2 * SIEVE_LIMIT as usize).into_iter().enumerate() {
+        assert_eq!(answer(n), expected, "{n}");
+        assert_eq!(reference(&Integer::from(n)), expected, "{n}");
+    }
+}
+
+#[test]
+fn boundaries() {
+    let one = Integer::from(1);
+    // Where the server changes algorithm or representation
+    let edges = [
+        Integer::from(VERIFIED_BELOW),
+        Integer::from(u64::MAX),
+        one.clone() << 64u32,
+        one.clone() << 128u32,
+        one << 512u32,
+    ];
+    for edge in &edges {
+        for n in around(edge, 200) {
+            assert_eq!(answer(&n), reference(&n), "{n}");
+        }
+    }
+}
+
+#[test]
+fn negative_numbers() {
+    for n in [-1, -2, -3, -7, -97, i64::MIN, i64::MIN + 1] {
+        assert!(!answer(n), "{n}");
+    }
+    let big = -(Integer::from(1) << 127u32) + 1;
+    assert!(!answer(big));
+}
+
+#[test]
+fn carmichael_numbers() {
+    let known = [
+        561u64, 1105, 1729, 2465, 2821, 6601, 8911, 10585, 15841, 29341, 41041, 46657, 52633,
+        62745, 63973, 75361, 101101, 115921, 126217, 162401, 172081, 188461, 252601, 278545,
+        294409, 314821, 334153, 340561, 399001, 410041, 449065, 488881, 512461,
+    ];
+    for n in known {
+        assert!(!answer(n), "{n}");
+    }
+    // Chernick's (6k + 1)(12k + 1)(18k + 1) is a Carmichael number whenever
+    // all three factors are prime, at every size
+    let mut found = 0;
+    for k in 1..20_000u64 {
+        let factors = [6 * k + 1, 12 * k + 1, 18 * k + 1].map(Integer::from);
+        if factors.iter().all(reference) {
+            let n: Integer = factors.iter().product();
+            assert!(!answer(&n), "{n}");
+            found += 1;
+        }
+    }
+    assert!(found > 20);
+}
+
+fn wide(words: Vec<u64>) -> Integer {
+    words
+        .into_iter()
+        .fold(Integer::new(), |n, word| (n << 64u32) + word)
+}
+
+proptest! {
+    #[test]
+    fn random_u64s(n in any::<u64>()) {
+        prop_assert_eq!(answer(n), reference(&Integer::from(n)));
+    }
+
+    #[test]
+    fn random_512_bit_numbers(words in prop::collection::vec(any::<u64>(), 8)) {
+        let n = wide(words);
+        prop_assert_eq!(answer(&n), reference(&n));
+    }
+
+    #[test]
+    fn random_512_bit_primes(words in prop::collection::vec(any::<u64>(), 8)) {
+        // Random numbers are almost never prime, so find some on purpose
+        let p = wide(words).next_prime();
+        prop_assert!(answer(&p), "{}", p);
+        prop_assert!(!answer(Integer::from(&p * &p)), "{}", p);
+    }
+}
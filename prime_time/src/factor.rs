@@ -0,0 +1,230 @@
+use crate::budget::{Budget, Interrupted};
+use crate::primality;
+use rug::Integer;
+use rug::ops::Pow;
+
+/// Divisors up to here are found by trial division; everything left after
+/// that has only factors above it
+pub const TRIAL_LIMIT: u32 = 1 << 10;
+
+// Steps of the rho walk between gcds
+const BATCH: u32 = 128;
+
+/// Prime factorization as `(p, e)` pairs in increasing order of `p`.
+///
+/// A negative `n` gets a leading `(-1, 1)`, zero factors as `[(0, 1)]` and one
+/// as the empty product.
+pub fn factorize(n: &Integer, budget: &Budget) -> Result<Vec<(Integer, u32)>, Interrupted> {
+    if *n == 0 {
+        return Ok(vec![(Integer::ZERO, 1)]);
+    }
+    let mut factors = vec![];
+    if *n < 0 {
+        factors.push((Integer::from(-1), 1));
+    }
+    let (found, _) = factor_until(&Integer::from(n.abs_ref()), budget, |_| false)?;
+    factors.extend(found);
+    Ok(factors)
+}
+
+/// Factors the positive `n` until `enough` is happy with the product of the
+/// prime powers found so far, which come back in order along with whatever
+/// is left unfactored
+pub fn factor_until(
+    n: &Integer,
+    budget: &Budget,
+    mut enough: impl FnMut(&Integer) -> bool,
+) -> Result<(Vec<(Integer, u32)>, Integer), Interrupted> {
+    let mut rest = n.clone();
+    let mut found = trial_divide(&mut rest);
+    let mut product: Integer = found
+        .iter()
+        .map(|(p, e)| Integer::from(p.pow(*e)))
+        .product();
+    let mut composites = vec![];
+    if rest > 1 {
+        composites.push(rest);
+    }
+    while !enough(&product)
+        && let Some(m) = composites.pop()
+    {
+        budget.check()?;
+        if primality::is_prime(&m, budget)? {
+            product *= &m;
+            found.push((m, 1));
+            continue;
+        }
+        let d = split(&m, budget)?;
+        let cofactor = Integer::from(&m / &d);
+        composites.push(d);
+        composites.push(cofactor);
+    }
+
+    found.sort();
+    let mut factors: Vec<(Integer, u32)> = vec![];
+    for (p, e) in found {
+        match factors.last_mut() {
+            Some((q, f)) if *q == p => *f += e,
+            _ => factors.push((p, e)),
+        }
+    }
+    Ok((factors, composites.into_iter().product()))
+}
+
+/// Strips the factors below [`TRIAL_LIMIT`] out of `n`
+fn trial_divide(n: &mut Integer) -> Vec<(Integer, u32)> {
+    let mut factors = vec![];
+    let candidates = std::iter::once(2).chain((3..TRIAL_LIMIT).step_by(2));
+    for d in candidates {
+        if Integer::from(d).pow(2) > *n {
+            break;
+        }
+        let mut e = 0;
+        while n.is_divisible_u(d) {
+            n.div_exact_u_mut(d);
+            e += 1;
+        }
+        if e > 0 {
+            factors.push((Integer::from(d), e));
+        }
+    }
+    // Whatever is left is 1, a prime, or a product of factors above the limit
+    if *n > 1 && n.to_u32().is_some_and(|m| m < TRIAL_LIMIT * TRIAL_LIMIT) {
+        factors.push((std::mem::replace(n, Integer::from(1)), 1));
+    }
+    factors
+}
+
+/// A non-trivial divisor of the composite `n`, which has no small factors
+fn split(n: &Integer, budget: &Budget) -> Result<Integer, Interrupted> {
+    // Rho is slow to separate repeated factors, so take squares apart directly
+    if n.is_perfect_square() {
+        return Ok(n.clone().sqrt());
+    }
+    for c in 1u32.. {
+        if let Some(d) = brent(n, c, budget)? {
+            return Ok(d);
+        }
+    }
+    unreachable!("Every composite eventually splits")
+}
+
+/// Brent's variant of Pollard's rho on `x -> x^2 + c`. Gives up with `None`
+/// when the walk cycles modulo every factor at once.
+fn brent(n: &Integer, c: u32, budget: &Budget) -> Result<Option<Integer>, Interrupted> {
+    let step = |x: &Integer| (Integer::from(x.square_ref()) + c).modulo(n);
+    let mut y = Integer::from(2);
+    let mut x = y.clone();
+    let mut saved = y.clone();
+    let mut q = Integer::from(1);
+    let mut g = Integer::from(1);
+    let mut r = 1u32;
+    while g == 1 {
+        x.clone_from(&y);
+        for _ in 0..r {
+            y = step(&y);
+        }
+        let mut k = 0;
+        while k < r && g == 1 {
+            budget.check()?;
+            saved.clone_from(&y);
+            for _ in 0..BATCH.min(r - k) {
+                y = step(&y);
+                q = (q * Integer::from(&x - &y).abs()).modulo(n);
+            }
+            g = q.clone().gcd(n);
+            k += BATCH;
+        }
+        r = r.saturating_mul(2);
+    }
+    if g == *n {
+        // The batch overshot; retrace it one step at a time
+        loop {
+            saved = step(&saved);
+            g = Integer::from(&x - &saved).abs().gcd(n);
+            if g > 1 {
+                break;
+            }
+        }
+    }
+    Ok((g != *n).then_some(g))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn factorize(n: impl Into<Integer>) -> Vec<(Integer, u32)> {
+        super::factorize(&n.into(), &Budget::unlimited()).unwrap()
+    }
+
+    fn pairs(factors: &[(i64, u32)]) -> Vec<(Integer, u32)> {
+        factors
+            .iter()
+            .map(|&(p, e)| (Integer::from(p), e))
+            .collect()
+    }
+
+    fn product(factors: &[(Integer, u32)]) -> Integer {
+        factors
+            .iter()
+            .map(|(p, e)| Integer::from(p.pow(*e)))
+            .product()
+    }
+
+    #[test]
+    fn edge_cases() {
+        assert_eq!(factorize(0), pairs(&[(0, 1)]));
+        assert_eq!(factorize(1), pairs(&[]));
+        assert_eq!(factorize(-1), pairs(&[(-1, 1)]));
+        assert_eq!(factorize(-12), pairs(&[(-1, 1), (2, 2), (3, 1)]));
+        assert_eq!(factorize(1021), pairs(&[(1021, 1)]));
+        assert_eq!(factorize(1021 * 1021), pairs(&[(1021, 2)]));
+        assert_eq!(factorize(1031 * 1031), pairs(&[(1031, 2)]));
+    }
+
+    #[test]
+    fn beyond_trial_division() {
+        // F6 = 2^64 + 1
+        let f6 = (Integer::from(1) << 64u32) + 1;
+        assert_eq!(factorize(f6), pairs(&[(274177, 1), (67280421310721, 1)]));
+        let p = Integer::from(4_294_967_291u64);
+        let q = Integer::from(4_294_967_279u64);
+        let n = Integer::from(&p * &q) * &p * 9;
+        assert_eq!(factorize(n), vec![(3.into(), 2), (q, 1), (p, 2)]);
+    }
+
+    #[test]
+    fn respects_budget() {
+        // Two 80-bit primes are far out of rho's reach
+        let p = Integer::from(1) << 79u32;
+        let p = p.next_prime();
+        let q = p.clone().next_prime();
+        let budget = Budget::with_timeout(std::time::Duration::from_millis(10));
+        assert_eq!(
+            super::factorize(&(p * q), &budget),
+            Err(Interrupted::OutOfTime)
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn factors_multiply_back(n in any::<i64>()) {
+            let factors = factorize(n);
+            let (sign, primes) = match factors.first() {
+                Some((p, _)) if *p < 0 => (-1, &factors[1..]),
+                _ => (1, &factors[..]),
+            };
+            if n != 0 {
+                prop_assert_eq!(product(primes) * sign, n);
+            }
+            for pair in primes.windows(2) {
+                prop_assert!(pair[0].0 < pair[1].0);
+            }
+            for (p, _) in primes {
+                prop_assert!(*p == 0 || p.is_probably_prime(30) != rug::integer::IsPrime::No);
+            }
+        }
+    }
+}
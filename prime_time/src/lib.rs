@@ -0,0 +1,3 @@
+//! The parts of prime_time a client needs too
+pub mod certificate;
+pub mod number;
@@ -1,13 +1,37 @@
-mod types;
+mod backend;
+mod budget;
+mod cache;
+mod certify;
+mod codec;
+mod config;
+mod connection;
+mod counting;
+#[cfg(test)]
+mod differential;
+mod factor;
+mod pool;
+mod primality;
+mod rpc;
+mod sieve;
+mod special;
+#[cfg(test)]
+mod test_util;
 mod verif;
-use anyhow::Result;
-use bytes::{BufMut, Bytes, BytesMut};
-use std::ops::ControlFlow;
-use std::time::Duration;
-use tokio::io::AsyncReadExt;
+mod verified;
+use clap::Parser;
+use config::Config;
+use sieve::Sieve;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::net::{TcpListener, TcpStream};
-use tracing::{debug, info};
-use tracing_subscriber::{fmt, prelude::*, EnvFilter};
+use tokio::select;
+use tokio::signal;
+use tokio::task::{JoinError, JoinSet};
+use tokio::time::{sleep, timeout};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+use tracing_subscriber::{EnvFilter, fmt, prelude::*};
+use verif::Engine;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -15,98 +39,113 @@ async fn main() -> anyhow::Result<()> {
         .with(fmt::layer())
         .with(EnvFilter::from_default_env())
         .init();
-    spawn_server().await?;
+    let config = Config::parse();
+    if let Err(e) = config.validate() {
+        e.exit();
+    }
+    spawn_server(config).await?;
     Ok(())
 }
 
-#[tracing::instrument]
-async fn spawn_server() -> anyhow::Result<()> {
-    let listener = TcpListener::bind("0.0.0.0:1337").await?;
-    info!("Listening...");
-    loop {
-        let (socket, _) = listener.accept().await?;
-        info!("Client connected");
-        tokio::spawn(async move { client(socket).await.unwrap() });
+#[tracing::instrument(skip(config))]
+async fn spawn_server(config: Config) -> anyhow::Result<()> {
+    let config = Arc::new(config);
+    let sieve = if config.sieve_limit > 0 {
+        let limit = config.sieve_limit;
+        let start = Instant::now();
+        let sieve = tokio::task::spawn_blocking(move || Sieve::new(limit)).await?;
+        info!(
+            limit = sieve.limit(),
+            primes = sieve.count(),
+            bytes = sieve.bytes(),
+            elapsed = ?start.elapsed(),
+            "Sieved small primes"
+        );
+        Some(Arc::new(sieve))
+    } else {
+        None
+    };
+    let engine = Arc::new(Engine::new(&config, sieve));
+    if config.cache_report_secs > 0 {
+        let period = Duration::from_secs(config.cache_report_secs);
+        tokio::spawn(report_cache(engine.clone(), period));
     }
-}
-
-#[tracing::instrument]
-async fn client(stream: TcpStream) -> anyhow::Result<()> {
-    let mut socket = SocketReader::new(stream);
+    let listener = TcpListener::bind(&config.listen).await?;
+    info!("Listening on {}", config.listen);
+    let token = CancellationToken::new();
+    let mut clients = JoinSet::new();
     loop {
-        let buf = socket.read().await?;
-        match buf {
-            None => break,
-            Some(buf) => {
-                debug!("Read bytes: {}", String::from_utf8_lossy(&buf));
-                if let ControlFlow::Break(()) =
-                    verif::process_requests(&buf, &mut socket.socket).await?
-                {
-                    break;
+        select! {
+            accept_result = listener.accept() => match accept_result {
+                Ok((socket, addr)) => {
+                    info!("Client connected from {addr}");
+                    clients.spawn(client(socket, engine.clone(), config.clone(), token.clone()));
                 }
-            }
+                Err(e) => {
+                    // Usually out of file descriptors; give some a chance to
+                    // close rather than spinning
+                    error!("Failed to accept a connection: {e}");
+                    sleep(Duration::from_millis(100)).await;
+                }
+            },
+            Some(joined) = clients.join_next() => reap(joined),
+            _ = signal::ctrl_c() => break,
         }
     }
-    info!("Client disconnected");
-    Ok(())
-}
-
-struct SocketReader {
-    socket: TcpStream,
-    slop_buffer: BytesMut,
-}
-
-impl SocketReader {
-    pub fn new(socket: TcpStream) -> Self {
-        Self {
-            socket,
-            slop_buffer: BytesMut::with_capacity(1024),
+    info!("Shutting down...");
+    drop(listener);
+    token.cancel();
+    let drained = timeout(config.shutdown_grace(), async {
+        while let Some(joined) = clients.join_next().await {
+            reap(joined);
         }
+    })
+    .await;
+    if drained.is_err() {
+        warn!(
+            "Dropping {} connections still open after the grace period",
+            clients.len()
+        );
+        clients.shutdown().await;
     }
+    Ok(())
+}
 
-    pub async fn read(&mut self) -> Result<Option<Vec<u8>>> {
-        let read = self.socket.read_buf(&mut self.slop_buffer).await?;
-        let p: &[u8] = &self.slop_buffer;
-        debug!("Contents of slop: {:?}", p);
-        if read == 0 {
-            Ok(None)
-        } else {
-            match self.last_newline() {
-                None => Ok(Some(vec![])),
-                Some(last_newline_idx) => {
-                    let to_return = self.slop_buffer.split_to(last_newline_idx);
-                    let _ = self.slop_buffer.split_to(1); // Drop the trailing newline
-                    Ok(Some(to_return.to_vec()))
-                }
-            }
-        }
+#[tracing::instrument(skip(engine, config, shutdown), fields(primality = ?config.primality))]
+async fn client(
+    stream: TcpStream,
+    engine: Arc<Engine>,
+    config: Arc<Config>,
+    shutdown: CancellationToken,
+) {
+    let (reader, writer) = stream.into_split();
+    match connection::serve(reader, writer, engine, &config, shutdown).await {
+        Ok(()) => info!("Client disconnected"),
+        Err(e) => error!("Client errored: {e}"),
     }
+}
 
-    fn last_newline(&self) -> Option<usize> {
-        let mut i = self.slop_buffer.len() - 1;
-        while i != 0 {
-            if self.slop_buffer[i] == b'\n' {
-                return Some(i);
-            }
-            i -= 1;
-        }
-        None
+fn reap(joined: Result<(), JoinError>) {
+    if let Err(e) = joined {
+        error!("Client task failed: {e}");
     }
 }
 
-async fn read_until_newline(socket: &mut TcpStream, buffer: &mut [u8]) -> Result<Option<usize>> {
-    let mut ptr = 0;
-    let timeout = Duration::from_secs(60);
+async fn report_cache(engine: Arc<Engine>, period: Duration) {
+    let mut ticks = tokio::time::interval(period);
+    ticks.tick().await;
     loop {
-        let read = tokio::time::timeout(timeout, socket.read(&mut buffer[ptr..])).await??;
-        if ptr == 0 && read == 0 {
-            return Ok(None);
-        } else if read == 0 {
-            return Ok(Some(ptr));
-        } else if buffer[ptr + read - 1] == b'\n' {
-            return Ok(Some(read + ptr));
-        } else {
-            ptr += read;
-        }
+        ticks.tick().await;
+        let Some(stats) = engine.cache_stats() else {
+            break;
+        };
+        info!(
+            hits = stats.hits,
+            misses = stats.misses,
+            evictions = stats.evictions,
+            entries = stats.entries,
+            pinned = stats.pinned,
+            "Result cache"
+        );
     }
 }
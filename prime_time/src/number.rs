@@ -0,0 +1,213 @@
+use rug::Integer;
+use rug::ops::Pow;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
+
+/// Integral values are materialized, so anything with more trailing zeros
+/// than this, like `1e1000000000`, is kept as written instead
+pub const MAX_EXPONENT: u32 = 10_000;
+
+/// Any number that can appear in a request, parsed exactly from its JSON text
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Number {
+    Integer(Integer),
+    /// A value with a non-zero fractional part, kept as written
+    NonInteger(String),
+    /// An integer that's a multiple of `10^MAX_EXPONENT`, kept as written
+    Huge(String),
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum Error {
+    #[error("Not a JSON number: {0}")]
+    Syntax(String),
+}
+
+impl Number {
+    /// Parses the text of a JSON number, eg `-12`, `1.5`, `2E+3`
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let syntax = || Error::Syntax(text.to_string());
+        let (negative, rest) = match text.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, text),
+        };
+        let (mantissa, exponent) = match rest.find(['e', 'E']) {
+            Some(idx) => (&rest[..idx], Some(&rest[idx + 1..])),
+            None => (rest, None),
+        };
+        let (int_part, frac_part) = match mantissa.split_once('.') {
+            Some((i, f)) => (i, f),
+            None => (mantissa, ""),
+        };
+        if int_part.is_empty() || !is_digits(int_part) || !is_digits(frac_part) {
+            return Err(syntax());
+        }
+        if mantissa.ends_with('.') {
+            return Err(syntax());
+        }
+
+        let mut digits = format!("{int_part}{frac_part}");
+        let trailing_zeros = digits.len() - digits.trim_end_matches('0').len();
+        digits.truncate(digits.len() - trailing_zeros);
+        if digits.trim_start_matches('0').is_empty() {
+            return Ok(Self::Integer(Integer::ZERO));
+        }
+
+        // value = digits * 10^scale, and `digits` no longer ends in a zero
+        let exponent = match exponent {
+            Some(exp) => parse_exponent(exp).ok_or_else(syntax)?,
+            None => 0,
+        };
+        let scale = exponent
+            .saturating_add(trailing_zeros as i64)
+            .saturating_sub(frac_part.len() as i64);
+        if scale < 0 {
+            return Ok(Self::NonInteger(text.to_string()));
+        }
+        if scale > MAX_EXPONENT as i64 {
+            return Ok(Self::Huge(text.to_string()));
+        }
+        let mut n: Integer = digits.parse().map_err(|_| syntax())?;
+        n *= Integer::from(10).pow(scale as u32);
+        if negative {
+            n = -n;
+        }
+        Ok(Self::Integer(n))
+    }
+}
+
+fn is_digits(s: &str) -> bool {
+    s.bytes().all(|b| b.is_ascii_digit())
+}
+
+fn parse_exponent(exp: &str) -> Option<i64> {
+    let (negative, digits) = match exp.as_bytes().first()? {
+        b'-' => (true, &exp[1..]),
+        b'+' => (false, &exp[1..]),
+        _ => (false, exp),
+    };
+    if digits.is_empty() || !is_digits(digits) {
+        return None;
+    }
+    // Anything past i64 is far beyond MAX_EXPONENT in either direction
+    let magnitude = digits.parse::<i64>().unwrap_or(i64::MAX);
+    Some(if negative { -magnitude } else { magnitude })
+}
+
+impl From<Integer> for Number {
+    fn from(n: Integer) -> Self {
+        Self::Integer(n)
+    }
+}
+
+impl Serialize for Number {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let text = match self {
+            Self::Integer(n) => n.to_string(),
+            Self::NonInteger(s) | Self::Huge(s) => s.clone(),
+        };
+        let n: serde_json::Number = text.parse().map_err(serde::ser::Error::custom)?;
+        n.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Number {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let n = serde_json::Number::deserialize(deserializer)?;
+        Self::parse(n.as_str()).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn int(n: i64) -> Number {
+        Number::Integer(n.into())
+    }
+
+    #[test]
+    fn integers() {
+        assert_eq!(Number::parse("0"), Ok(int(0)));
+        assert_eq!(Number::parse("-0"), Ok(int(0)));
+        assert_eq!(Number::parse("17"), Ok(int(17)));
+        assert_eq!(Number::parse("-17"), Ok(int(-17)));
+        assert_eq!(Number::parse("100"), Ok(int(100)));
+        let big = "123456789012345678901234567890123456789";
+        assert_eq!(
+            Number::parse(big),
+            Ok(Number::Integer(big.parse().unwrap()))
+        );
+    }
+
+    #[test]
+    fn integral_floats_and_exponents() {
+        assert_eq!(Number::parse("1e2"), Ok(int(100)));
+        assert_eq!(Number::parse("1E+2"), Ok(int(100)));
+        assert_eq!(Number::parse("2.0"), Ok(int(2)));
+        assert_eq!(Number::parse("-0.0"), Ok(int(0)));
+        assert_eq!(Number::parse("0e-5"), Ok(int(0)));
+        assert_eq!(Number::parse("1.25e2"), Ok(int(125)));
+        assert_eq!(Number::parse("1500e-2"), Ok(int(15)));
+        assert_eq!(Number::parse("-7.000e0"), Ok(int(-7)));
+    }
+
+    #[test]
+    fn non_integers() {
+        for text in ["1.5", "-1.5", "2e-1", "1.25e1", "1e-99999999999999999999"] {
+            assert_eq!(
+                Number::parse(text),
+                Ok(Number::NonInteger(text.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn exponent_limit() {
+        assert!(matches!(Number::parse("1e10000"), Ok(Number::Integer(_))));
+        for text in ["1e10001", "-1e10001", "1.5e10002", "1e99999999999999999999"] {
+            assert_eq!(Number::parse(text), Ok(Number::Huge(text.to_string())));
+        }
+        // Zero is zero no matter the exponent
+        assert_eq!(Number::parse("0e99999999"), Ok(int(0)));
+    }
+
+    #[test]
+    fn syntax_errors() {
+        for text in ["", "-", "1.", ".5", "1e", "1e+", "abc", "1x"] {
+            assert_eq!(Number::parse(text), Err(Error::Syntax(text.to_string())));
+        }
+    }
+
+    #[test]
+    fn deserialize_from_json() {
+        let n: Number = serde_json::from_str("2e3").unwrap();
+        assert_eq!(n, int(2000));
+        let n: Number = serde_json::from_str("1.5").unwrap();
+        assert_eq!(n, Number::NonInteger("1.5".to_string()));
+        let big = "98765432109876543210987654321";
+        let n: Number = serde_json::from_str(big).unwrap();
+        assert_eq!(n, Number::Integer(big.parse().unwrap()));
+        assert_eq!(serde_json::to_string(&n).unwrap(), big);
+    }
+
+    proptest! {
+        #[test]
+        fn matches_f64_for_small_values(x in -1_000_000.0f64..1_000_000.0f64) {
+            let text = x.to_string();
+            match Number::parse(&text).unwrap() {
+                Number::Integer(n) => prop_assert_eq!(n, Integer::from_f64(x).unwrap()),
+                Number::NonInteger(_) => prop_assert!(x.fract() != 0.0),
+                Number::Huge(_) => prop_assert!(false, "{} isn't huge", text),
+            }
+        }
+
+        #[test]
+        fn integer_roundtrip(x in any::<i64>()) {
+            let n = int(x);
+            let text = serde_json::to_string(&n).unwrap();
+            prop_assert_eq!(serde_json::from_str::<Number>(&text).unwrap(), n);
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use crate::budget::{Budget, Interrupted};
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot};
+use tracing::error;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed set of OS threads for CPU-heavy work, so that it never runs on
+/// (and stalls) a tokio worker. Submission waits once `queue` jobs are pending.
+pub struct ComputePool {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl ComputePool {
+    pub fn new(threads: usize, queue: usize) -> Self {
+        let (jobs, rx) = mpsc::channel::<Job>(queue);
+        let rx = Arc::new(Mutex::new(rx));
+        for i in 0..threads {
+            let rx = rx.clone();
+            std::thread::Builder::new()
+                .name(format!("compute-{i}"))
+                .spawn(move || worker(rx))
+                .expect("Failed to spawn compute thread");
+        }
+        Self { jobs }
+    }
+
+    /// Runs `f` on the pool. The budget is cancelled if this future is dropped
+    /// or the deadline passes, and `f` is expected to notice via
+    /// [`Budget::check`].
+    pub async fn run<T, E, F>(&self, budget: Budget, f: F) -> Result<T, E>
+    where
+        T: Send + 'static,
+        E: From<Interrupted> + Send + 'static,
+        F: FnOnce(&Budget) -> Result<T, E> + Send + 'static,
+    {
+        let _cancel = CancelOnDrop(budget.clone());
+        let (tx, rx) = oneshot::channel();
+        let job_budget = budget.clone();
+        let job: Job = Box::new(move || {
+            let _ = tx.send(f(&job_budget));
+        });
+        let work = async {
+            if self.jobs.send(job).await.is_err() {
+                return Err(Interrupted::Cancelled.into());
+            }
+            // The sender is dropped without a result only if the job panicked
+            match rx.await {
+                Ok(result) => result,
+                Err(_) => Err(Interrupted::Cancelled.into()),
+            }
+        };
+        match budget.deadline() {
+            Some(deadline) => match tokio::time::timeout_at(deadline.into(), work).await {
+                Ok(result) => result,
+                Err(_) => Err(Interrupted::OutOfTime.into()),
+            },
+            None => work.await,
+        }
+    }
+}
+
+fn worker(rx: Arc<Mutex<mpsc::Receiver<Job>>>) {
+    loop {
+        let job = rx.lock().unwrap().blocking_recv();
+        match job {
+            Some(job) => {
+                if catch_unwind(AssertUnwindSafe(job)).is_err() {
+                    error!("Compute job panicked");
+                }
+            }
+            None => break,
+        }
+    }
+}
+
+struct CancelOnDrop(Budget);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    fn spin(budget: &Budget) -> Result<(), Interrupted> {
+        loop {
+            budget.check()?;
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[tokio::test]
+    async fn runs_jobs() {
+        let pool = ComputePool::new(2, 4);
+        let r = pool
+            .run(Budget::unlimited(), |_| Ok::<_, Interrupted>(6 * 7))
+            .await;
+        assert_eq!(r, Ok(42));
+    }
+
+    #[tokio::test]
+    async fn deadline_is_enforced() {
+        let pool = ComputePool::new(1, 1);
+        let budget = Budget::with_timeout(Duration::from_millis(20));
+        assert_eq!(pool.run(budget, spin).await, Err(Interrupted::OutOfTime));
+        // The worker noticed and is free again
+        assert_eq!(
+            pool.run(Budget::unlimited(), |_| Ok::<_, Interrupted>(1))
+                .await,
+            Ok(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn dropping_cancels() {
+        let pool = ComputePool::new(1, 1);
+        let budget = Budget::unlimited();
+        let handle = budget.clone();
+        let run = pool.run(budget, spin);
+        let _ = tokio::time::timeout(Duration::from_millis(20), run).await;
+        assert_eq!(handle.check(), Err(Interrupted::Cancelled));
+        assert_eq!(
+            pool.run(Budget::unlimited(), |_| Ok::<_, Interrupted>(1))
+                .await,
+            Ok(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn survives_panics() {
+        let pool = ComputePool::new(1, 1);
+        let r: Result<(), Interrupted> = pool.run(Budget::unlimited(), |_| panic!("boom")).await;
+        assert_eq!(r, Err(Interrupted::Cancelled));
+        assert_eq!(
+            pool.run(Budget::unlimited(), |_| Ok::<_, Interrupted>(1))
+                .await,
+            Ok(1)
+        );
+    }
+}
@@ -0,0 +1,416 @@
+use crate::budget::{Budget, Interrupted};
+use rug::Integer;
+
+const SMALL_PRIMES: [u64; 12] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37];
+
+// Checking the first 12 primes as bases is deterministic below
+// 318665857834031151167461 (about 3.18 * 10^23), which covers every u64
+const U64_WITNESSES: [u64; 12] = SMALL_PRIMES;
+
+pub fn is_prime(n: &Integer, budget: &Budget) -> Result<bool, Interrupted> {
+    match n.to_u64() {
+        Some(n) => Ok(is_prime_u64(n)),
+        None if *n < 0 => Ok(false),
+        None => bpsw(n, budget),
+    }
+}
+
+/// The smallest prime above `n`
+pub fn next_prime(n: &Integer, budget: &Budget) -> Result<Integer, Interrupted> {
+    if *n < 2 {
+        return Ok(Integer::from(2));
+    }
+    // The first odd number above `n`
+    let mut candidate = Integer::from(n + 1u32) | 1u32;
+    while !is_prime(&candidate, budget)? {
+        budget.check()?;
+        candidate += 2;
+    }
+    Ok(candidate)
+}
+
+/// The largest prime below `n`, if there is one
+pub fn prev_prime(n: &Integer, budget: &Budget) -> Result<Option<Integer>, Interrupted> {
+    if *n <= 3 {
+        return Ok((*n == 3).then(|| Integer::from(2)));
+    }
+    // The first odd number below `n`, which is at least 3
+    let mut candidate = Integer::from(n - 1u32);
+    if candidate.is_even() {
+        candidate -= 1;
+    }
+    while !is_prime(&candidate, budget)? {
+        budget.check()?;
+        candidate -= 2;
+    }
+    Ok(Some(candidate))
+}
+
+/// Deterministic Miller-Rabin
+pub fn is_prime_u64(n: u64) -> bool {
+    if n < 2 {
+        return false;
+    }
+    for p in SMALL_PRIMES {
+        if n.is_multiple_of(p) {
+            return n == p;
+        }
+    }
+    let (d, s) = split_twos_u64(n - 1);
+    U64_WITNESSES
+        .iter()
+        .all(|&a| strong_probable_prime_u64(n, a, d, s))
+}
+
+fn split_twos_u64(n: u64) -> (u64, u32) {
+    let s = n.trailing_zeros();
+    (n >> s, s)
+}
+
+fn mul_mod(a: u64, b: u64, m: u64) -> u64 {
+    ((a as u128 * b as u128) % m as u128) as u64
+}
+
+fn pow_mod(mut base: u64, mut exp: u64, m: u64) -> u64 {
+    let mut acc = 1;
+    base %= m;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            acc = mul_mod(acc, base, m);
+        }
+        base = mul_mod(base, base, m);
+        exp >>= 1;
+    }
+    acc
+}
+
+// `n - 1 = d * 2^s` with `d` odd
+fn strong_probable_prime_u64(n: u64, a: u64, d: u64, s: u32) -> bool {
+    let mut x = pow_mod(a, d, n);
+    if x == 1 || x == n - 1 {
+        return true;
+    }
+    for _ in 1..s {
+        x = mul_mod(x, x, n);
+        if x == n - 1 {
+            return true;
+        }
+    }
+    false
+}
+
+/// Baillie-PSW: a strong probable prime test to base 2 followed by a strong
+/// Lucas probable prime test. No counterexample is known.
+pub fn bpsw(n: &Integer, budget: &Budget) -> Result<bool, Interrupted> {
+    if *n < 2 {
+        return Ok(false);
+    }
+    for p in SMALL_PRIMES {
+        if n.is_divisible_u(p as u32) {
+            return Ok(*n == p);
+        }
+    }
+    Ok(strong_probable_prime(n, &Integer::from(2), budget)?
+        && strong_lucas_probable_prime(n, budget)?)
+}
+
+fn strong_probable_prime(n: &Integer, a: &Integer, budget: &Budget) -> Result<bool, Interrupted> {
+    let n_minus_one = Integer::from(n - 1);
+    let s = n_minus_one.find_one(0).unwrap_or(0);
+    let d = Integer::from(&n_minus_one >> s);
+    let mut x = Integer::from(a.pow_mod_ref(&d, n).unwrap());
+    if x == 1 || x == n_minus_one {
+        return Ok(true);
+    }
+    for _ in 1..s {
+        budget.check()?;
+        x.square_mut();
+        x %= n;
+        if x == n_minus_one {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// Selfridge's method A: the first D in 5, -7, 9, -11, ... with (D/n) = -1
+fn selfridge_d(n: &Integer) -> Option<i64> {
+    let mut d: i64 = 5;
+    loop {
+        match Integer::from(d).jacobi(n) {
+            -1 => return Some(d),
+            0 if Integer::from(d).abs() != *n => return None,
+            _ => {}
+        }
+        d = if d > 0 { -(d + 2) } else { -d + 2 };
+    }
+}
+
+fn strong_lucas_probable_prime(n: &Integer, budget: &Budget) -> Result<bool, Interrupted> {
+    // Otherwise no suitable D exists and the search never ends
+    if n.is_perfect_square() {
+        return Ok(false);
+    }
+    let Some(d) = selfridge_d(n) else {
+        return Ok(false);
+    };
+    let p = Integer::from(1);
+    let q = Integer::from((1 - d) / 4);
+    let d = Integer::from(d);
+
+    let n_plus_one = Integer::from(n + 1);
+    let s = n_plus_one.find_one(0).unwrap_or(0);
+    let k = Integer::from(&n_plus_one >> s);
+
+    let half = |mut x: Integer| {
+        if x.is_odd() {
+            x += n;
+        }
+        x >>= 1;
+        x
+    };
+
+    // Walk the bits of k computing U_k, V_k and Q^k, all mod n
+    let mut u = Integer::from(1);
+    let mut v = p.clone();
+    let mut qk = q.clone().modulo(n);
+    for bit in (0..k.significant_bits() - 1).rev() {
+        budget.check()?;
+        u = (u * &v) % n;
+        v = (v.square() - Integer::from(&qk << 1)).modulo(n);
+        qk = qk.square() % n;
+        if k.get_bit(bit) {
+            let u_next = half(Integer::from(&p * &u) + &v);
+            let v_next = half(Integer::from(&d * &u) + Integer::from(&p * &v));
+            u = u_next.modulo(n);
+            v = v_next.modulo(n);
+            qk = (qk * &q).modulo(n);
+        }
+    }
+
+    if u == 0 || v == 0 {
+        return Ok(true);
+    }
+    for _ in 1..s {
+        budget.check()?;
+        v = (v.square() - Integer::from(&qk << 1)).modulo(n);
+        if v == 0 {
+            return Ok(true);
+        }
+        qk = qk.square() % n;
+    }
+    Ok(false)
+}
+
+/// Below this, `verified::is_prime_sqrt` takes at most 2^16 divisions,
+/// cheap enough to prefer the proven check. Its proof covers every u64, but
+/// near 2^64 it would take 2^32 divisions where Miller-Rabin takes a dozen
+/// exponentiations.
+pub const VERIFIED_BELOW: u64 = 1 << 32;
+
+/// The old `O(sqrt(n))` check: hopeless for big primes, but obviously right
+pub fn trial_division(x: &Integer, budget: &Budget) -> Result<bool, Interrupted> {
+    if *x <= 1 {
+        return Ok(false);
+    }
+    let bound = x.clone().sqrt() + Integer::ONE;
+    let mut i: Integer = 2.into();
+    while i < bound {
+        budget.check()?;
+        if x.is_divisible(&i) {
+            return Ok(false);
+        }
+        i += Integer::ONE;
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::sieve;
+    use proptest::prelude::*;
+    use rug::integer::IsPrime;
+
+    fn is_prime(n: &Integer) -> bool {
+        super::is_prime(n, &Budget::unlimited()).unwrap()
+    }
+
+    fn bpsw(n: &Integer) -> bool {
+        super::bpsw(n, &Budget::unlimited()).unwrap()
+    }
+
+    const CARMICHAEL: [u64; 12] = [
+        561, 1105, 1729, 2465, 2821, 6601, 8911, 10585, 15841, 29341, 41041, 825265,
+    ];
+
+    // Strong pseudoprimes to base 2, and to every prime base up to 7 / 37
+    const STRONG_PSEUDOPRIMES: [u128; 8] = [
+        2047,
+        3277,
+        4033,
+        4681,
+        8321,
+        3215031751,
+        3825123056546413051,
+        318665857834031151167461,
+    ];
+
+    #[test]
+    fn dense_small_range() {
+        let reference = sieve(100_000);
+        for (n, expected) in reference.iter().enumerate() {
+            assert_eq!(is_prime_u64(n as u64), *expected, "{n}");
+            assert_eq!(bpsw(&Integer::from(n)), *expected, "{n}");
+        }
+    }
+
+    #[test]
+    fn agrees_with_trial_division() {
+        for n in (1u64 << 32)..(1u64 << 32) + 1_000 {
+            let expected = trial_division(&Integer::from(n), &Budget::unlimited()).unwrap();
+            assert_eq!(is_prime_u64(n), expected, "{n}");
+            assert_eq!(bpsw(&Integer::from(n)), expected, "{n}");
+        }
+    }
+
+    #[test]
+    fn carmichael_numbers() {
+        for n in CARMICHAEL {
+            assert!(!is_prime_u64(n), "{n}");
+            assert!(!bpsw(&Integer::from(n)), "{n}");
+        }
+    }
+
+    #[test]
+    fn large_carmichael_numbers() {
+        // Chernick: (6k+1)(12k+1)(18k+1) is Carmichael when all three are prime
+        let mut found = 0;
+        let mut k = Integer::from(1u64 << 22);
+        while found < 5 {
+            k += 1;
+            let factors: [Integer; 3] = [6u32, 12, 18].map(|c| Integer::from(&k * c) + 1);
+            if factors
+                .iter()
+                .all(|f| f.is_probably_prime(40) != IsPrime::No)
+            {
+                let n: Integer = factors.iter().product();
+                assert!(n > u64::MAX);
+                assert!(!is_prime(&n), "{n}");
+                found += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn strong_pseudoprimes() {
+        for n in STRONG_PSEUDOPRIMES {
+            let n = Integer::from(n);
+            assert!(
+                strong_probable_prime(&n, &Integer::from(2), &Budget::unlimited()).unwrap(),
+                "{n}"
+            );
+            assert!(!is_prime(&n), "{n}");
+            assert!(!bpsw(&n), "{n}");
+        }
+    }
+
+    #[test]
+    fn composite_fermat_numbers() {
+        // Composite Fermat numbers are all strong pseudoprimes to base 2, so
+        // these are only caught by the Lucas half of BPSW
+        for k in 5..=8 {
+            let f = (Integer::from(1) << (1u32 << k)) + 1;
+            assert!(
+                strong_probable_prime(&f, &Integer::from(2), &Budget::unlimited()).unwrap(),
+                "F{k}"
+            );
+            assert!(
+                !strong_lucas_probable_prime(&f, &Budget::unlimited()).unwrap(),
+                "F{k}"
+            );
+            assert!(!is_prime(&f), "F{k}");
+        }
+    }
+
+    #[test]
+    fn known_large_primes() {
+        for p in [61, 89, 107, 127, 521, 607] {
+            let m = (Integer::from(1) << p as u32) - 1;
+            assert!(is_prime(&m), "2^{p} - 1");
+            assert!(!is_prime(&(m + 2)), "2^{p} + 1");
+        }
+        assert!(is_prime_u64(u64::MAX - 58));
+        assert!(!is_prime_u64(u64::MAX));
+        assert!(is_prime(&Integer::from(18446744073709551629u128)));
+    }
+
+    #[test]
+    fn negative_numbers() {
+        for n in [-1i64, -2, -3, -7, i64::MIN] {
+            assert!(!is_prime(&Integer::from(n)));
+        }
+        assert!(!is_prime(&-(Integer::from(1) << 127u32)));
+    }
+
+    #[test]
+    fn respects_budget() {
+        let m = (Integer::from(1) << 4423u32) - 1;
+        let budget = Budget::unlimited();
+        budget.cancel();
+        assert_eq!(super::is_prime(&m, &budget), Err(Interrupted::Cancelled));
+        // Small numbers never need the budget
+        assert_eq!(super::is_prime(&Integer::from(7), &budget), Ok(true));
+    }
+
+    #[test]
+    fn neighbouring_primes() {
+        let next = |n: i64| next_prime(&n.into(), &Budget::unlimited()).unwrap();
+        let prev = |n: i64| prev_prime(&n.into(), &Budget::unlimited()).unwrap();
+        assert_eq!(next(-5), 2);
+        assert_eq!(next(2), 3);
+        assert_eq!(next(3), 5);
+        assert_eq!(next(89), 97);
+        assert_eq!(prev(2), None);
+        assert_eq!(prev(3), Some(2.into()));
+        assert_eq!(prev(4), Some(3.into()));
+        assert_eq!(prev(97), Some(89.into()));
+        // 2^127 - 1 and the prime just below 2^128
+        let m127: Integer = (Integer::from(1) << 127u32) - 1;
+        let below = Integer::from(&m127 - 1u32);
+        assert_eq!(next_prime(&below, &Budget::unlimited()), Ok(m127.clone()));
+        let m = prev_prime(&(Integer::from(1) << 128u32), &Budget::unlimited());
+        assert_eq!(m, Ok(Some((Integer::from(1) << 128u32) - 159)));
+    }
+
+    proptest! {
+        #[test]
+        fn u64_agrees_with_gmp(n in any::<u64>()) {
+            let expected = Integer::from(n).is_probably_prime(50) != IsPrime::No;
+            prop_assert_eq!(is_prime_u64(n), expected);
+            prop_assert_eq!(bpsw(&Integer::from(n)), expected);
+        }
+
+        #[test]
+        fn bpsw_agrees_with_gmp(hi in any::<u64>(), lo in any::<u64>()) {
+            let n = (Integer::from(hi) << 64u32) + lo;
+            let expected = n.is_probably_prime(50) != IsPrime::No;
+            prop_assert_eq!(is_prime(&n), expected);
+        }
+
+        #[test]
+        fn bpsw_finds_primes(hi in any::<u64>(), lo in any::<u64>()) {
+            let p = ((Integer::from(hi) << 64u32) + lo).next_prime();
+            prop_assert!(is_prime(&p));
+        }
+
+        #[test]
+        fn neighbours_agree_with_gmp(n in any::<u64>()) {
+            let n = Integer::from(n) << 32u32;
+            let next = next_prime(&n, &Budget::unlimited()).unwrap();
+            prop_assert_eq!(&next, &n.clone().next_prime());
+            let prev = prev_prime(&next, &Budget::unlimited()).unwrap().unwrap();
+            prop_assert!(prev <= n && Integer::from(&prev + 1u32).next_prime() == next);
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use crate::verif::{Engine, Error, Request};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use tokio_util::sync::CancellationToken;
+
+const VERSION: &str = "2.0";
+
+// Error codes reserved by the spec, then the first one left to servers
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const GAVE_UP: i64 = -32000;
+
+/// Whether a line is JSON-RPC rather than the plain protocol, once JSON-RPC is
+/// switched on. A call names its version, and the plain protocol has no batches.
+pub fn is_rpc(value: &Value) -> bool {
+    match value {
+        Value::Array(_) => true,
+        Value::Object(fields) => fields.contains_key("jsonrpc"),
+        _ => false,
+    }
+}
+
+/// Answers a call or a batch of calls with the line to send back, which is
+/// empty when everything in it was a notification
+pub async fn answer(engine: &Engine, value: Value, token: &CancellationToken) -> Vec<u8> {
+    let text = match value {
+        Value::Array(calls) if calls.is_empty() => {
+            let reply = Reply::new(Value::Null, Outcome::error(INVALID_REQUEST, "Empty batch"));
+            serde_json::to_string(&reply)
+        }
+        Value::Array(calls) => {
+            let mut replies = vec![];
+            for value in calls {
+                replies.extend(call(engine, value, token).await);
+            }
+            if replies.is_empty() {
+                return vec![];
+            }
+            serde_json::to_string(&replies)
+        }
+        value => match call(engine, value, token).await {
+            Some(reply) => serde_json::to_string(&reply),
+            None => return vec![],
+        },
+    };
+    format!("{}\n", text.unwrap()).into_bytes()
+}
+
+/// Answers a single call, or returns nothing for a notification
+async fn call(engine: &Engine, value: Value, token: &CancellationToken) -> Option<Reply> {
+    let Value::Object(mut fields) = value else {
+        let outcome = Outcome::error(INVALID_REQUEST, "Call is not a JSON object");
+        return Some(Reply::new(Value::Null, outcome));
+    };
+    let id = fields.remove("id");
+    // A call too broken to tell whether it was a notification still gets an
+    // answer, as the spec asks
+    let invalid = |message| {
+        let outcome = Outcome::error(INVALID_REQUEST, message);
+        Some(Reply::new(id.clone().unwrap_or(Value::Null), outcome))
+    };
+    if fields.remove("jsonrpc") != Some(Value::from(VERSION)) {
+        return invalid("`jsonrpc` must be \"2.0\"");
+    }
+    let Some(Value::String(method)) = fields.remove("method") else {
+        return invalid("`method` must be a string");
+    };
+    let params = match fields.remove("params") {
+        None => Map::new(),
+        Some(Value::Object(params)) => params,
+        Some(_) => return invalid("`params` must be an object"),
+    };
+
+    let result = match Request::from_params(method, params) {
+        Ok(request) => engine.process(request, token).await,
+        Err(e) => Err(e),
+    };
+    let outcome = match result {
+        Ok(response) => match response.into_result() {
+            Ok(result) => Outcome::Result(result),
+            Err(reason) => Outcome::error(GAVE_UP, &reason),
+        },
+        Err(e) => Outcome::error(code(&e), &e.to_string()),
+    };
+    Some(Reply::new(id?, outcome))
+}
+
+fn code(e: &Error) -> i64 {
+    match e {
+        Error::UnknownMethod(_) => METHOD_NOT_FOUND,
+        Error::Interrupted(_) => GAVE_UP,
+        // Everything else that can go wrong past the envelope is in `params`
+        _ => INVALID_PARAMS,
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Reply {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(flatten)]
+    outcome: Outcome,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Outcome {
+    Result(Value),
+    Error { code: i64, message: String },
+}
+
+impl Reply {
+    fn new(id: Value, outcome: Outcome) -> Self {
+        Self {
+            jsonrpc: VERSION,
+            id,
+            outcome,
+        }
+    }
+}
+
+impl Outcome {
+    fn error(code: i64, message: &str) -> Self {
+        Self::Error {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Config;
+    use clap::Parser;
+    use serde_json::json;
+
+    async fn exchange(line: &str) -> Option<Value> {
+        let engine = Engine::new(&Config::parse_from(["prime_time", "--json-rpc"]), None);
+        let value = serde_json::from_str(line).unwrap();
+        assert!(is_rpc(&value));
+        let out = answer(&engine, value, &CancellationToken::new()).await;
+        let out = String::from_utf8(out).unwrap();
+        (!out.is_empty()).then(|| {
+            assert!(out.ends_with('\n'));
+            serde_json::from_str(&out).unwrap()
+        })
+    }
+
+    #[tokio::test]
+    async fn single_calls() {
+        let reply =
+            exchange(r#"{"jsonrpc":"2.0","id":1,"method":"isPrime","params":{"number":7}}"#).await;
+        assert_eq!(
+            reply,
+            Some(json!({"jsonrpc": "2.0", "id": 1, "result": {"prime": true}}))
+        );
+        let reply =
+            exchange(r#"{"jsonrpc":"2.0","id":"a","method":"factorize","params":{"number":12}}"#)
+                .await;
+        assert_eq!(
+            reply,
+            Some(json!({"jsonrpc": "2.0", "id": "a", "result": {"factors": [[2, 2], [3, 1]]}}))
+        );
+        // Big answers stay exact
+        let reply = exchange(
+            r#"{"jsonrpc":"2.0","id":null,"method":"nextPrime","params":{"number":1e20}}"#,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            reply["result"]["prime"].to_string(),
+            "100000000000000000039"
+        );
+        assert_eq!(reply["id"], Value::Null);
+    }
+
+    #[tokio::test]
+    async fn errors() {
+        for (line, code) in [
+            (
+                r#"{"jsonrpc":"2.0","id":1,"method":"isPrim","params":{"number":7}}"#,
+                METHOD_NOT_FOUND,
+            ),
+            (
+                r#"{"jsonrpc":"2.0","id":1,"method":"isPrime","params":{"number":"7"}}"#,
+                INVALID_PARAMS,
+            ),
+            (
+                r#"{"jsonrpc":"2.0","id":1,"method":"isPrime"}"#,
+                INVALID_PARAMS,
+            ),
+            (
+                r#"{"jsonrpc":"2.0","id":1,"method":"isPrime","params":[7]}"#,
+                INVALID_REQUEST,
+            ),
+            (
+                r#"{"jsonrpc":"1.0","id":1,"method":"isPrime","params":{"number":7}}"#,
+                INVALID_REQUEST,
+            ),
+            (r#"{"jsonrpc":"2.0","id":1,"method":7}"#, INVALID_REQUEST),
+            (
+                r#"{"jsonrpc":"2.0","id":1,"method":"nextPrime","params":{"number":1e100000}}"#,
+                INVALID_PARAMS,
+            ),
+        ] {
+            let reply = exchange(line).await.unwrap();
+            assert_eq!(reply["id"], 1, "{line}");
+            assert_eq!(reply["error"]["code"], code, "{line}");
+            assert!(reply["error"]["message"].is_string(), "{line}");
+            assert!(reply.get("result").is_none(), "{line}");
+        }
+    }
+
+    #[tokio::test]
+    async fn notifications() {
+        let line = r#"{"jsonrpc":"2.0","method":"isPrime","params":{"number":7}}"#;
+        assert_eq!(exchange(line).await, None);
+        // Failing calls without an id stay quiet too, unless they are too
+        // malformed to be sure they were notifications
+        let line = r#"{"jsonrpc":"2.0","method":"isPrim","params":{"number":7}}"#;
+        assert_eq!(exchange(line).await, None);
+        let line = r#"{"jsonrpc":"2.0","method":7}"#;
+        let reply = exchange(line).await.unwrap();
+        assert_eq!(reply["id"], Value::Null);
+        assert_eq!(reply["error"]["code"], INVALID_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn batches() {
+        let line = r#"[
+            {"jsonrpc":"2.0","id":1,"method":"isPrime","params":{"number":4}},
+            {"jsonrpc":"2.0","method":"isPrime","params":{"number":5}},
+            7,
+            {"jsonrpc":"2.0","id":2,"method":"isPrimeMany","params":{"numbers":[2,3,4]}}
+        ]"#;
+        let reply = exchange(line).await.unwrap();
+        assert_eq!(
+            reply,
+            json!([
+                {"jsonrpc": "2.0", "id": 1, "result": {"prime": false}},
+                {"jsonrpc": "2.0", "id": null, "error": {"code": INVALID_REQUEST, "message": "Call is not a JSON object"}},
+                {"jsonrpc": "2.0", "id": 2, "result": {"primes": [true, true, false]}},
+            ])
+        );
+        let reply = exchange("[]").await.unwrap();
+        assert_eq!(reply["error"]["code"], INVALID_REQUEST);
+        let line = r#"[{"jsonrpc":"2.0","method":"isPrime","params":{"number":5}}]"#;
+        assert_eq!(exchange(line).await, None);
+    }
+}
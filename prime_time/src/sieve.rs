@@ -0,0 +1,124 @@
+// Each word covers 128 numbers, only the odd ones stored
+const SPAN: u64 = 128;
+
+// Sieve this many words at a time, which keeps a segment in L1
+const SEGMENT: usize = 4096;
+
+/// A bitset over the odd numbers below `limit`, set for primes
+pub struct Sieve {
+    limit: u64,
+    bits: Vec<u64>,
+}
+
+impl Sieve {
+    pub fn new(limit: u64) -> Self {
+        let mut bits = vec![u64::MAX; limit.div_ceil(SPAN) as usize];
+        let base = odd_primes_to(limit.isqrt());
+        for (i, segment) in bits.chunks_mut(SEGMENT).enumerate() {
+            let lo = (i * SEGMENT) as u64 * SPAN;
+            let hi = lo + segment.len() as u64 * SPAN;
+            for &p in &base {
+                // The first odd multiple in range that has no smaller factor
+                let mut m = (p * p).max(lo.div_ceil(p) * p);
+                if m.is_multiple_of(2) {
+                    m += p;
+                }
+                while m < hi {
+                    let bit = (m - lo) / 2;
+                    segment[(bit / 64) as usize] &= !(1 << (bit % 64));
+                    m += 2 * p;
+                }
+            }
+        }
+        if let Some(first) = bits.first_mut() {
+            // 1 isn't prime
+            *first &= !1;
+        }
+        // Nor is anything at or past the limit
+        for n in ((limit | 1)..bits.len() as u64 * SPAN).step_by(2) {
+            bits[(n / SPAN) as usize] &= !(1 << ((n / 2) % 64));
+        }
+        Self { limit, bits }
+    }
+
+    /// Whether `n` is prime, if it's below the limit
+    pub fn get(&self, n: u64) -> Option<bool> {
+        if n >= self.limit {
+            None
+        } else if n.is_multiple_of(2) {
+            Some(n == 2)
+        } else {
+            Some(self.bits[(n / SPAN) as usize] >> ((n / 2) % 64) & 1 == 1)
+        }
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    /// The number of primes below the limit
+    pub fn count(&self) -> u64 {
+        let odd: u64 = self.bits.iter().map(|word| word.count_ones() as u64).sum();
+        odd + (self.limit > 2) as u64
+    }
+
+    /// Bytes held by the bitset
+    pub fn bytes(&self) -> usize {
+        self.bits.len() * size_of::<u64>()
+    }
+}
+
+/// The odd primes up to and including `limit`, by a plain sieve
+fn odd_primes_to(limit: u64) -> Vec<u64> {
+    let limit = limit as usize;
+    let mut composite = vec![false; limit + 1];
+    let mut primes = vec![];
+    for i in (3..=limit).step_by(2) {
+        if !composite[i] {
+            primes.push(i as u64);
+            for j in (i * i..=limit).step_by(2 * i) {
+                composite[j] = true;
+            }
+        }
+    }
+    primes
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::primality::is_prime_u64;
+
+    #[test]
+    fn matches_miller_rabin() {
+        // A few segments, ending partway through a word
+        let limit = 3 * SEGMENT as u64 * SPAN + 77;
+        let sieve = Sieve::new(limit);
+        let mut count = 0;
+        for n in 0..limit {
+            assert_eq!(sieve.get(n), Some(is_prime_u64(n)), "{n}");
+            count += is_prime_u64(n) as u64;
+        }
+        assert_eq!(sieve.get(limit), None);
+        assert_eq!(sieve.count(), count);
+    }
+
+    #[test]
+    fn tiny_limits() {
+        for limit in 0..10 {
+            let sieve = Sieve::new(limit);
+            let expected = (0..limit).filter(|&n| is_prime_u64(n)).count() as u64;
+            assert_eq!(sieve.count(), expected, "{limit}");
+            for n in 0..12 {
+                assert_eq!(sieve.get(n), (n < limit).then(|| is_prime_u64(n)));
+            }
+        }
+    }
+
+    #[test]
+    fn known_count() {
+        let sieve = Sieve::new(100_000_000);
+        assert_eq!(sieve.count(), 5_761_455);
+        assert_eq!(sieve.bytes(), 100_000_000 / 16);
+    }
+}
@@ -0,0 +1,211 @@
+use crate::budget::{Budget, Interrupted};
+use rug::Integer;
+
+/// Shapes of number with a primality test of their own, cheaper than a
+/// general one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Form {
+    /// `2^p - 1`
+    Mersenne { p: u32 },
+    /// `2^e + 1`
+    Fermat { e: u32 },
+    /// `k * 2^e + 1` with `k` odd and below `2^e`
+    Proth { k: Integer, e: u32 },
+}
+
+/// Which special form `n` takes, read off its bits
+pub fn detect(n: &Integer) -> Option<Form> {
+    if *n < 3 {
+        return None;
+    }
+    let bits = n.significant_bits();
+    if n.count_ones() == Some(bits) {
+        return Some(Form::Mersenne { p: bits });
+    }
+    if n.is_even() {
+        return None;
+    }
+    let m = Integer::from(n - 1);
+    let e = m.find_one(0).unwrap();
+    let k = m >> e;
+    if k == 1 {
+        Some(Form::Fermat { e })
+    } else if k.significant_bits() <= e {
+        Some(Form::Proth { k, e })
+    } else {
+        None
+    }
+}
+
+/// Whether `n`, which takes the given `form`, is prime
+pub fn is_prime(n: &Integer, form: &Form, budget: &Budget) -> Result<bool, Interrupted> {
+    match *form {
+        Form::Mersenne { p } => lucas_lehmer(n, p, budget),
+        Form::Fermat { e } => Ok(pepin(n, e)),
+        Form::Proth { .. } => proth(n, budget),
+    }
+}
+
+fn is_prime_exponent(p: u32) -> bool {
+    p >= 2
+        && (2..)
+            .take_while(|d| d * d <= p)
+            .all(|d| !p.is_multiple_of(d))
+}
+
+/// Lucas-Lehmer: `2^p - 1` is prime exactly when `s_(p-2) = 0`, where
+/// `s_0 = 4` and `s_(i+1) = s_i^2 - 2`, all modulo `2^p - 1`
+fn lucas_lehmer(n: &Integer, p: u32, budget: &Budget) -> Result<bool, Interrupted> {
+    // 2^ab - 1 has 2^a - 1 as a factor
+    if !is_prime_exponent(p) {
+        return Ok(false);
+    }
+    if p == 2 {
+        return Ok(true);
+    }
+    let mut s = Integer::from(4);
+    for _ in 0..p - 2 {
+        budget.check()?;
+        s.square_mut();
+        s -= 2;
+        if s < 0 {
+            s += n;
+        }
+        // 2^p = 1, so the bits past p fold back onto the bottom
+        while s.significant_bits() > p {
+            let high = Integer::from(&s >> p);
+            s.keep_bits_mut(p);
+            s += high;
+        }
+        if s == *n {
+            s = Integer::ZERO;
+        }
+    }
+    Ok(s == 0)
+}
+
+/// Pépin: the Fermat number `2^e + 1`, for `e > 1`, is prime exactly when
+/// `3^((n-1)/2) = -1 (mod n)`
+fn pepin(n: &Integer, e: u32) -> bool {
+    // 2^e + 1 has 2^(e/d) + 1 as a factor for every odd d dividing e
+    if !e.is_power_of_two() {
+        return false;
+    }
+    e == 1 || euler_is_minus_one(n, &Integer::from(3))
+}
+
+/// Proth: if `a^((n-1)/2) = -1 (mod n)` for some `a` then `n` is prime, and
+/// if `n` is prime any quadratic non-residue `a` will do
+fn proth(n: &Integer, budget: &Budget) -> Result<bool, Interrupted> {
+    // Squares are the only numbers without a non-residue to find
+    if n.is_perfect_square() {
+        return Ok(false);
+    }
+    for a in 3u32.. {
+        budget.check()?;
+        let a = Integer::from(a);
+        match a.jacobi(n) {
+            -1 => return Ok(euler_is_minus_one(n, &a)),
+            // A common factor
+            0 => return Ok(false),
+            _ => {}
+        }
+    }
+    unreachable!("Non-squares have non-residues")
+}
+
+fn euler_is_minus_one(n: &Integer, a: &Integer) -> bool {
+    let m = Integer::from(n - 1);
+    let half = Integer::from(&m >> 1);
+    Integer::from(a.pow_mod_ref(&half, n).unwrap()) == m
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::prelude::*;
+    use rug::integer::IsPrime;
+
+    // Every p below 4500 with 2^p - 1 prime
+    const MERSENNE_EXPONENTS: [u32; 20] = [
+        2, 3, 5, 7, 13, 17, 19, 31, 61, 89, 107, 127, 521, 607, 1279, 2203, 2281, 3217, 4253, 4423,
+    ];
+
+    // Every n up to 600 with 3 * 2^n + 1 prime
+    const PROTH_THREES: [u32; 19] = [
+        1, 2, 5, 6, 8, 12, 18, 30, 36, 41, 66, 189, 201, 209, 276, 353, 408, 438, 534,
+    ];
+
+    fn test(n: &Integer) -> Option<bool> {
+        let form = detect(n)?;
+        Some(is_prime(n, &form, &Budget::unlimited()).unwrap())
+    }
+
+    fn one() -> Integer {
+        Integer::from(1)
+    }
+
+    #[test]
+    fn forms() {
+        assert_eq!(detect(&Integer::from(2)), None);
+        assert_eq!(detect(&Integer::from(7)), Some(Form::Mersenne { p: 3 }));
+        assert_eq!(detect(&Integer::from(17)), Some(Form::Fermat { e: 4 }));
+        assert_eq!(
+            detect(&Integer::from(13)),
+            Some(Form::Proth { k: 3.into(), e: 2 })
+        );
+        // k = 5 is too big next to 2^2
+        assert_eq!(detect(&Integer::from(21)), None);
+        assert_eq!(detect(&Integer::from(1000)), None);
+    }
+
+    #[test]
+    fn known_mersenne_exponents() {
+        for p in 2..4500 {
+            let n = (one() << p) - 1;
+            let expected = MERSENNE_EXPONENTS.contains(&p);
+            assert_eq!(test(&n), Some(expected), "2^{p} - 1");
+        }
+    }
+
+    #[test]
+    fn fermat_numbers() {
+        // F_0 to F_4 are the only known Fermat primes
+        for m in 0..12 {
+            let n = (one() << (1u32 << m)) + 1;
+            assert_eq!(test(&n), Some(m <= 4), "F_{m}");
+        }
+        // Not Fermat numbers, but composite all the same
+        assert_eq!(test(&((one() << 24) + 1)), Some(false));
+    }
+
+    #[test]
+    fn proth_numbers() {
+        for e in 2..600 {
+            let n = (Integer::from(3) << e) + 1;
+            assert_eq!(test(&n), Some(PROTH_THREES.contains(&e)), "3 * 2^{e} + 1");
+        }
+        // A square: 7^2 = 3 * 2^4 + 1
+        assert_eq!(test(&Integer::from(49)), Some(false));
+    }
+
+    #[test]
+    fn respects_budget() {
+        let budget = Budget::unlimited();
+        budget.cancel();
+        let n = (one() << 4423) - 1;
+        let form = detect(&n).unwrap();
+        assert_eq!(is_prime(&n, &form, &budget), Err(Interrupted::Cancelled));
+    }
+
+    proptest! {
+        #[test]
+        fn proth_agrees_with_gmp(k in any::<u64>(), extra in 1u32..200) {
+            let k = Integer::from(k | 1);
+            let n: Integer = (k.clone() << (k.significant_bits() + extra)) + 1;
+            prop_assume!(matches!(detect(&n), Some(Form::Proth { .. })));
+            let expected = n.is_probably_prime(50) != IsPrime::No;
+            prop_assert_eq!(test(&n), Some(expected));
+        }
+    }
+}
@@ -0,0 +1,18 @@
+//! Helpers shared by the test modules
+
+/// Whether each number below `limit` is prime, by a plain sieve of
+/// Eratosthenes that shares no code with the server
+pub fn sieve(limit: usize) -> Vec<bool> {
+    let mut prime = vec![true; limit];
+    prime[..2.min(limit)].fill(false);
+    let mut i = 2;
+    while i * i < limit {
+        if prime[i] {
+            for j in (i * i..limit).step_by(i) {
+                prime[j] = false;
+            }
+        }
+        i += 1;
+    }
+    prime
+}
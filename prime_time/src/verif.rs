@@ -1,110 +1,503 @@
-use anyhow::Result;
+use crate::backend::PrimalityTest;
+use crate::budget::{Budget, Interrupted};
+use crate::cache::{self, PrimeCache};
+use crate::certify;
+use crate::codec;
+use crate::config::Config;
+use crate::counting;
+use crate::factor;
+use crate::pool::ComputePool;
+use crate::primality;
+use crate::rpc;
+use crate::sieve::Sieve;
+use prime_time::certificate::Certificate;
+use prime_time::number::{MAX_EXPONENT, Number};
 use rug::Integer;
-use serde::{Deserialize, Serialize};
-use std::ops::ControlFlow;
+use serde::Serialize;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
-use tracing::error;
+use tokio::select;
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize)]
 pub struct Request {
     method: String,
-    number: Integer,
+    #[serde(flatten)]
+    params: Params,
 }
 
+/// What a request is about, serialized as the field it's read from
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+enum Params {
+    Number(Number),
+    Numbers(Vec<Number>),
+}
+
+const METHODS: &[&str] = &[
+    "isPrime",
+    "factorize",
+    "nextPrime",
+    "prevPrime",
+    "nthPrime",
+    "primeCount",
+    "isPrimeMany",
+    "certifyPrime",
+];
+
+/// The longest `numbers` array accepted by `isPrimeMany`
+const MAX_BATCH: usize = 10_000;
+
 #[derive(Debug, Error)]
 pub enum Error {
-    #[error("Invalid method: {0}")]
-    InvalidMethod(String),
-}
-
-pub async fn process_requests(buf: &[u8], stream: &mut TcpStream) -> Result<ControlFlow<()>> {
-    let source = String::from_utf8_lossy(buf);
-    let rs = process_requests_(source.lines());
-    let buf: Vec<u8> = rs
-        .responses
-        .into_iter()
-        .flat_map(|response| response.serialize())
-        .collect();
-    stream.write_all(&buf).await?;
-    if !rs.ok {
-        stream.write_all(b"malformed request").await?;
-        Ok(ControlFlow::Break(()))
-    } else {
-        Ok(ControlFlow::Continue(()))
-    }
-}
-
-#[derive(Debug)]
-struct Responses {
-    responses: Vec<Response>,
-    ok: bool,
-}
-
-fn process_requests_<'a>(lines: impl Iterator<Item = &'a str>) -> Responses {
-    let mut responses = vec![];
-    for line in lines {
-        match process_request(line) {
-            Ok(response) => responses.push(response),
-            Err(e) => {
-                error!("Error process request: {e}");
-                return Responses {
-                    responses,
-                    ok: false,
-                };
-            }
+    #[error(transparent)]
+    Framing(#[from] codec::Error),
+    #[error("Request is not valid UTF-8: {0}")]
+    InvalidUtf8(#[from] std::str::Utf8Error),
+    #[error("Request is not valid JSON: {0}")]
+    InvalidJson(#[from] serde_json::Error),
+    #[error("Request is not a JSON object")]
+    NotAnObject,
+    #[error("Request has no `method` field")]
+    MissingMethod,
+    #[error("`method` must be a string, got: {0}")]
+    MethodNotString(serde_json::Value),
+    #[error("Unknown method: {0}")]
+    UnknownMethod(String),
+    #[error("Request has no `number` field")]
+    MissingNumber,
+    #[error("Request has no `numbers` field")]
+    MissingNumbers,
+    #[error("`numbers` must be an array, got: {0}")]
+    NumbersNotArray(serde_json::Value),
+    #[error("`numbers` has {0} elements, more than the {MAX_BATCH} allowed")]
+    TooManyNumbers(usize),
+    #[error("Element {index} of `numbers`: {source}")]
+    InBatch { index: usize, source: Box<Error> },
+    #[error("`number` must be a number, got the string: {0:?}")]
+    NumberIsString(String),
+    #[error("`number` must be a number, got the bool: {0}")]
+    NumberIsBool(bool),
+    #[error("`number` must be a number, got: {0}")]
+    NumberNotNumeric(serde_json::Value),
+    #[error(transparent)]
+    Number(#[from] prime_time::number::Error),
+    #[error("`{method}` needs an integer, got: {number}")]
+    NotAnInteger { method: String, number: String },
+    #[error("`{method}` can't work with numbers past 10^{MAX_EXPONENT}, got: {number}")]
+    TooLarge { method: String, number: String },
+    #[error("`{method}` needs {expected}, got: {number}")]
+    OutOfRange {
+        method: String,
+        number: Integer,
+        expected: String,
+    },
+    #[error("Gave up on request: {0}")]
+    Interrupted(#[from] Interrupted),
+}
+
+impl Error {
+    /// A stable, machine-readable name for the kind of error
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Framing(_) => "line_too_long",
+            Self::InvalidUtf8(_) => "invalid_utf8",
+            Self::InvalidJson(_) => "invalid_json",
+            Self::NotAnObject => "not_an_object",
+            Self::MissingMethod => "missing_method",
+            Self::MethodNotString(_) => "invalid_method",
+            Self::UnknownMethod(_) => "unknown_method",
+            Self::MissingNumber | Self::MissingNumbers => "missing_number",
+            Self::NumberIsString(_)
+            | Self::NumberIsBool(_)
+            | Self::NumberNotNumeric(_)
+            | Self::NumbersNotArray(_)
+            | Self::Number(_) => "invalid_number",
+            Self::TooManyNumbers(_) => "batch_too_large",
+            Self::InBatch { source, .. } => source.code(),
+            Self::NotAnInteger { .. } => "not_an_integer",
+            Self::TooLarge { .. } => "number_too_large",
+            Self::OutOfRange { .. } => "out_of_range",
+            Self::Interrupted(Interrupted::OutOfTime) => "timeout",
+            Self::Interrupted(Interrupted::Cancelled) => "cancelled",
         }
     }
-    Responses {
-        responses,
-        ok: true,
+
+    /// Whether the lines after the one that caused this can still be trusted
+    pub fn is_recoverable(&self) -> bool {
+        !matches!(
+            self,
+            Self::Framing(_) | Self::Interrupted(Interrupted::Cancelled)
+        )
     }
 }
 
-#[tracing::instrument(skip(buf))]
-fn process_request(buf: &str) -> anyhow::Result<Response> {
-    let request: Request = serde_json::from_str(buf)?;
-    request.process()
+/// Everything a connection needs to answer requests
+pub struct Engine {
+    pool: ComputePool,
+    offload_bits: u32,
+    request_budget: Option<Duration>,
+    cache: Option<PrimeCache>,
+    primality: Arc<dyn PrimalityTest>,
+    json_rpc: bool,
 }
 
-impl Request {
-    pub fn process(self) -> anyhow::Result<Response> {
-        if self.method == "isPrime" {
-            Ok(Response::new(is_prime_opt(self.number)))
+impl Engine {
+    /// An engine for `config`, looking small numbers up in `sieve` where
+    /// its primality test uses one
+    pub fn new(config: &Config, sieve: Option<Arc<Sieve>>) -> Self {
+        Self {
+            pool: ComputePool::new(config.compute_threads, config.compute_queue),
+            offload_bits: config.offload_bits,
+            request_budget: config.request_budget(),
+            primality: config.primality.build(sieve),
+            json_rpc: config.json_rpc,
+            cache: NonZeroUsize::new(config.cache_capacity)
+                .map(|capacity| PrimeCache::new(capacity, config.cache_pin_below)),
+        }
+    }
+
+    pub fn cache_stats(&self) -> Option<cache::Stats> {
+        self.cache.as_ref().map(PrimeCache::stats)
+    }
+
+    /// Answers one line, in whichever protocol it was written in, with the
+    /// bytes to send back. JSON-RPC errors are part of the answer; only a
+    /// bad line in the plain protocol is an `Err`. Without `json_rpc`,
+    /// everything is the plain protocol.
+    pub async fn answer(&self, line: &[u8], token: &CancellationToken) -> Result<Vec<u8>, Error> {
+        let value = parse_json(line)?;
+        if self.json_rpc && rpc::is_rpc(&value) {
+            return Ok(rpc::answer(self, value, token).await);
+        }
+        let response = self.process(Request::from_value(value)?, token).await?;
+        Ok(response.serialize().collect())
+    }
+
+    /// Answers one request. Big numbers are tested on the compute pool, and
+    /// that work is abandoned as soon as `token` is cancelled.
+    pub async fn process(
+        &self,
+        request: Request,
+        token: &CancellationToken,
+    ) -> Result<Response, Error> {
+        let key = self.cache.as_ref().and(request.cache_key()).cloned();
+        if let Some(prime) = self.lookup(key.as_ref()) {
+            debug!("Cache hit for {request:?}");
+            return Ok(Response::new(prime));
+        }
+        // There's nothing cheaper to fall back on when factoring takes too
+        // long, so that gets an answer saying so instead of an error. Other
+        // methods are the plain protocol's, where the only error is a
+        // malformed request.
+        let reports_timeout = request.method == "factorize";
+        let response = match self.compute(request, token).await {
+            Err(Error::Interrupted(e @ Interrupted::OutOfTime)) if reports_timeout => {
+                Ok(Response::gave_up("factorize", e))
+            }
+            response => response,
+        };
+        if let (Some(cache), Some(key), Ok(response)) = (&self.cache, key, &response) {
+            cache.insert(key, response.prime().expect("Only isPrime is cached"));
+        }
+        response
+    }
+
+    fn lookup(&self, key: Option<&Integer>) -> Option<bool> {
+        self.cache.as_ref()?.get(key?)
+    }
+
+    async fn compute(
+        &self,
+        request: Request,
+        token: &CancellationToken,
+    ) -> Result<Response, Error> {
+        let budget = match self.request_budget {
+            Some(timeout) => Budget::with_timeout(timeout),
+            None => Budget::unlimited(),
+        };
+        let primality = self.primality.clone();
+        if request.is_expensive(self.offload_bits) {
+            debug!("Offloading {request:?}");
+            let work = move |budget: &Budget| request.process(&*primality, budget);
+            select! {
+                response = self.pool.run(budget, work) => response,
+                _ = token.cancelled() => Err(Interrupted::Cancelled.into()),
+            }
         } else {
-            Err(Error::InvalidMethod(self.method))?
+            request.process(&*primality, &budget)
         }
     }
 }
 
-fn is_prime_opt(x: Integer) -> bool {
-    if x <= 1 {
-        false
-    } else {
-        let bound = x.clone().sqrt() + Integer::ONE;
-        let mut i: Integer = 2.into();
-        while i < bound {
-            if x.clone() % i.clone() == Integer::ZERO {
-                return false;
+impl Request {
+    #[cfg(test)]
+    pub fn parse(buf: &[u8]) -> Result<Self, Error> {
+        Self::from_value(parse_json(buf)?)
+    }
+
+    /// Validates a single request. Unknown extra fields are ignored.
+    pub fn from_value(value: serde_json::Value) -> Result<Self, Error> {
+        let serde_json::Value::Object(mut fields) = value else {
+            return Err(Error::NotAnObject);
+        };
+        let method = match fields.remove("method") {
+            None => return Err(Error::MissingMethod),
+            Some(serde_json::Value::String(method)) => method,
+            Some(other) => return Err(Error::MethodNotString(other)),
+        };
+        Self::from_params(method, fields)
+    }
+
+    /// Validates a request given its method and the rest of its fields
+    pub fn from_params(
+        method: String,
+        mut fields: serde_json::Map<String, serde_json::Value>,
+    ) -> Result<Self, Error> {
+        if !METHODS.contains(&method.as_str()) {
+            return Err(Error::UnknownMethod(method));
+        }
+        let params = if method == "isPrimeMany" {
+            let values = match fields.remove("numbers") {
+                None => return Err(Error::MissingNumbers),
+                Some(serde_json::Value::Array(values)) => values,
+                Some(other) => return Err(Error::NumbersNotArray(other)),
+            };
+            if values.len() > MAX_BATCH {
+                return Err(Error::TooManyNumbers(values.len()));
+            }
+            let numbers = values
+                .into_iter()
+                .enumerate()
+                .map(|(index, value)| {
+                    parse_number(value).map_err(|e| Error::InBatch {
+                        index,
+                        source: Box::new(e),
+                    })
+                })
+                .collect::<Result<_, _>>()?;
+            Params::Numbers(numbers)
+        } else {
+            let value = fields.remove("number").ok_or(Error::MissingNumber)?;
+            Params::Number(parse_number(value)?)
+        };
+        Ok(Self { method, params })
+    }
+
+    /// The number to cache the answer under, for integers that are worth it;
+    /// anything below 2 is answered instantly anyway
+    pub fn cache_key(&self) -> Option<&Integer> {
+        match &self.params {
+            Params::Number(Number::Integer(n)) if *n >= 2 && self.method == "isPrime" => Some(n),
+            _ => None,
+        }
+    }
+
+    /// Whether answering is worth sending to the compute pool
+    pub fn is_expensive(&self, offload_bits: u32) -> bool {
+        let n = match &self.params {
+            Params::Number(Number::Integer(n)) => n,
+            Params::Number(Number::NonInteger(_) | Number::Huge(_)) => return false,
+            // The work grows with every number in the batch
+            Params::Numbers(numbers) => {
+                let bits = numbers.iter().map(|number| match number {
+                    Number::Integer(n) => n.significant_bits(),
+                    Number::NonInteger(_) | Number::Huge(_) => 0,
+                });
+                return bits.sum::<u32>() > offload_bits;
             }
-            i += Integer::ONE;
+        };
+        match self.method.as_str() {
+            // Trial division alone finishes off anything up to the limit squared
+            "factorize" | "certifyPrime" => n.significant_bits() > 2 * factor::TRIAL_LIMIT.ilog2(),
+            // These build their tables on first use, and only get slower after
+            "nthPrime" | "primeCount" => true,
+            _ => n.significant_bits() > offload_bits,
         }
-        true
+    }
+
+    #[tracing::instrument(skip(primality, budget))]
+    pub fn process(
+        self,
+        primality: &dyn PrimalityTest,
+        budget: &Budget,
+    ) -> Result<Response, Error> {
+        let Self { method, params } = self;
+        let number = match params {
+            Params::Number(number) if method == "isPrime" => {
+                return Ok(Response::new(is_prime(&number, primality, budget)?));
+            }
+            Params::Numbers(numbers) => {
+                let primes = numbers
+                    .iter()
+                    .map(|number| is_prime(number, primality, budget))
+                    .collect::<Result<_, _>>()?;
+                return Ok(Response::primes(primes));
+            }
+            Params::Number(number) => number,
+        };
+        let n = match number {
+            Number::Integer(n) => n,
+            Number::NonInteger(number) => return Err(Error::NotAnInteger { method, number }),
+            Number::Huge(number) => return Err(Error::TooLarge { method, number }),
+        };
+        let out_of_range = |number, expected| Error::OutOfRange {
+            method: method.clone(),
+            number,
+            expected,
+        };
+        match method.as_str() {
+            "factorize" => Ok(Response::factors(factor::factorize(&n, budget)?)),
+            "certifyPrime" => Ok(Response::certified(certify::certify(&n, budget)?)),
+            "nextPrime" => Ok(Response::found(
+                "nextPrime",
+                primality::next_prime(&n, budget)?,
+            )),
+            "prevPrime" => match primality::prev_prime(&n, budget)? {
+                Some(p) => Ok(Response::found("prevPrime", p)),
+                None => Err(out_of_range(n, "a number above 2".to_string())),
+            },
+            "nthPrime" => match n.to_u64().filter(|k| (1..=counting::MAX_NTH).contains(k)) {
+                Some(k) => Ok(Response::found(
+                    "nthPrime",
+                    counting::nth_prime(k, budget)?.into(),
+                )),
+                None => Err(out_of_range(
+                    n,
+                    format!("an index from 1 to {}", counting::MAX_NTH),
+                )),
+            },
+            "primeCount" if n < 2 => Ok(Response::count(0)),
+            "primeCount" => match n.to_u64().filter(|&x| x <= counting::MAX_COUNT) {
+                Some(x) => Ok(Response::count(counting::prime_count(x, budget)?)),
+                None => Err(out_of_range(
+                    n,
+                    format!("a number up to {}", counting::MAX_COUNT),
+                )),
+            },
+            _ => Err(Error::UnknownMethod(method)),
+        }
+    }
+}
+
+fn parse_json(buf: &[u8]) -> Result<serde_json::Value, Error> {
+    let source = std::str::from_utf8(buf)?;
+    Ok(serde_json::from_str(source)?)
+}
+
+fn parse_number(value: serde_json::Value) -> Result<Number, Error> {
+    match value {
+        serde_json::Value::Number(n) => Ok(Number::parse(n.as_str())?),
+        serde_json::Value::String(s) => Err(Error::NumberIsString(s)),
+        serde_json::Value::Bool(b) => Err(Error::NumberIsBool(b)),
+        other => Err(Error::NumberNotNumeric(other)),
+    }
+}
+
+fn is_prime(
+    number: &Number,
+    primality: &dyn PrimalityTest,
+    budget: &Budget,
+) -> Result<bool, Interrupted> {
+    match number {
+        Number::Integer(n) => primality.is_prime(n, budget),
+        // Only integers can be prime, and a huge one is a multiple of 10
+        Number::NonInteger(_) | Number::Huge(_) => Ok(false),
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize)]
 pub struct Response {
     method: &'static str,
-    prime: bool,
+    #[serde(flatten)]
+    answer: Answer,
+}
+
+/// The method-specific part of a response, serialized as a single field
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+enum Answer {
+    Prime(bool),
+    Primes(Vec<bool>),
+    Factors(Vec<(Number, u32)>),
+    #[serde(rename = "prime")]
+    Found(Number),
+    Count(u64),
+    Certificate(Certificate),
+    Error(String),
 }
 
 impl Response {
     fn new(prime: bool) -> Self {
         Self {
             method: "isPrime",
-            prime,
+            answer: Answer::Prime(prime),
+        }
+    }
+
+    fn primes(primes: Vec<bool>) -> Self {
+        Self {
+            method: "isPrimeMany",
+            answer: Answer::Primes(primes),
+        }
+    }
+
+    fn factors(factors: Vec<(Integer, u32)>) -> Self {
+        let factors = factors.into_iter().map(|(p, e)| (p.into(), e)).collect();
+        Self {
+            method: "factorize",
+            answer: Answer::Factors(factors),
+        }
+    }
+
+    fn found(method: &'static str, prime: Integer) -> Self {
+        Self {
+            method,
+            answer: Answer::Found(prime.into()),
+        }
+    }
+
+    /// A certificate for a prime, or just `prime: false`
+    fn certified(certificate: Option<Certificate>) -> Self {
+        let answer = match certificate {
+            Some(certificate) => Answer::Certificate(certificate),
+            None => Answer::Prime(false),
+        };
+        Self {
+            method: "certifyPrime",
+            answer,
+        }
+    }
+
+    fn count(count: u64) -> Self {
+        Self {
+            method: "primeCount",
+            answer: Answer::Count(count),
+        }
+    }
+
+    fn gave_up(method: &'static str, reason: Interrupted) -> Self {
+        Self {
+            method,
+            answer: Answer::Error(reason.to_string()),
+        }
+    }
+
+    /// The method-specific part of the answer, or why there isn't one
+    pub fn into_result(self) -> Result<serde_json::Value, String> {
+        match self.answer {
+            Answer::Error(reason) => Err(reason),
+            answer => Ok(serde_json::to_value(answer).unwrap()),
+        }
+    }
+
+    pub fn prime(&self) -> Option<bool> {
+        match self.answer {
+            Answer::Prime(prime) => Some(prime),
+            _ => None,
         }
     }
 
@@ -114,52 +507,45 @@ impl Response {
     }
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
+/// What gets sent back for a bad request when JSON errors are enabled
+#[derive(Debug, Serialize)]
+pub struct ErrorResponse {
+    error: ErrorBody,
+}
 
-    use proptest::prelude::*;
-    fn request() -> impl Strategy<Value = super::Request> {
-        (-1000i64..=10000i64).prop_map(|number| super::Request {
-            method: "isPrime".to_string(),
-            number,
-        })
-    }
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    code: &'static str,
+    message: String,
+    /// The request's line on the connection, counting from 1
+    line: usize,
+}
 
-    fn response() -> impl Strategy<Value = super::Response> {
-        proptest::bool::ANY.prop_map(|prime| super::Response {
-            method: "isPrime",
-            prime,
-        })
+impl ErrorResponse {
+    pub fn new(error: &Error, line: usize) -> Self {
+        Self {
+            error: ErrorBody {
+                code: error.code(),
+                message: error.to_string(),
+                line,
+            },
+        }
     }
 
-    fn requests() -> impl Strategy<Value = Vec<super::Request>> {
-        proptest::collection::vec(request(), 0..40)
+    pub fn serialize(self) -> impl Iterator<Item = u8> {
+        let s = serde_json::to_string(&self).unwrap();
+        format!("{s}\n").bytes().collect::<Vec<u8>>().into_iter()
     }
+}
 
-    #[derive(Debug)]
-    struct Malformed {
-        before_malformed: usize,
-        buf: String,
-    }
+#[cfg(test)]
+mod test {
+    use super::*;
 
-    fn malformed() -> impl Strategy<Value = Malformed> {
-        use std::iter::once;
-        let strats = (requests(), gibberish(), requests());
-        strats.prop_map(|(before, gib, after)| Malformed {
-            before_malformed: before.len(),
-            buf: before
-                .iter()
-                .map(|r| serde_json::to_string(r).unwrap())
-                .chain(once(gib))
-                .chain(after.iter().map(|r| serde_json::to_string(r).unwrap()))
-                .collect::<Vec<_>>()
-                .join("\n"),
-        })
-    }
+    use proptest::prelude::*;
 
-    fn gibberish() -> impl Strategy<Value = String> {
-        proptest::string::string_regex("a-zA-Z").unwrap()
+    fn response() -> impl Strategy<Value = super::Response> {
+        proptest::bool::ANY.prop_map(super::Response::new)
     }
 
     fn is_prime(x: i64) -> bool {
@@ -175,11 +561,360 @@ mod test {
         }
     }
 
+    #[tokio::test]
+    async fn any_number_is_well_formed() {
+        for (line, prime) in [
+            (r#"{"method":"isPrime","number":7}"#, true),
+            (r#"{"method":"isPrime","number":7.0}"#, true),
+            (r#"{"method":"isPrime","number":1.5}"#, false),
+            (r#"{"method":"isPrime","number":-3.25}"#, false),
+            (r#"{"method":"isPrime","number":2e3}"#, false),
+            (r#"{"method":"isPrime","number":1e2}"#, false),
+            (r#"{"method":"isPrime","number":0.2e1}"#, true),
+            (
+                r#"{"method":"isPrime","number":1234567891234567891234568}"#,
+                false,
+            ),
+        ] {
+            let response = process(line.as_bytes()).await.unwrap();
+            assert_eq!(response.prime(), Some(prime), "{line}");
+        }
+    }
+
+    #[test]
+    fn malformed_cases() {
+        let parse = |buf: &[u8]| Request::parse(buf).unwrap_err();
+        assert!(matches!(
+            parse(b"{\"method\":\"isPrime\",\"number\":\xff}"),
+            Error::InvalidUtf8(_)
+        ));
+        assert!(matches!(parse(b"{\"method\":"), Error::InvalidJson(_)));
+        assert!(matches!(parse(b""), Error::InvalidJson(_)));
+        assert!(matches!(parse(b"7"), Error::NotAnObject));
+        assert!(matches!(parse(br#"{"number":7}"#), Error::MissingMethod));
+        assert!(matches!(
+            parse(br#"{"method":7,"number":7}"#),
+            Error::MethodNotString(_)
+        ));
+        assert!(matches!(
+            parse(br#"{"method":"isPrim","number":7}"#),
+            Error::UnknownMethod(m) if m == "isPrim"
+        ));
+        assert!(matches!(
+            parse(br#"{"method":"isPrime"}"#),
+            Error::MissingNumber
+        ));
+        assert!(matches!(
+            parse(br#"{"method":"isPrime","number":"7"}"#),
+            Error::NumberIsString(s) if s == "7"
+        ));
+        assert!(matches!(
+            parse(br#"{"method":"isPrime","number":true}"#),
+            Error::NumberIsBool(true)
+        ));
+        assert!(matches!(
+            parse(br#"{"method":"isPrime","number":null}"#),
+            Error::NumberNotNumeric(_)
+        ));
+        assert!(matches!(
+            parse(br#"{"method":"isPrime","number":[7]}"#),
+            Error::NumberNotNumeric(_)
+        ));
+    }
+
+    #[test]
+    fn extra_fields_allowed() {
+        let request =
+            Request::parse(br#"{"method":"isPrime","extra":[1,{"a":null}],"number":13}"#).unwrap();
+        assert_eq!(
+            request
+                .process(
+                    &*crate::backend::Backend::Auto.build(None),
+                    &Budget::unlimited()
+                )
+                .unwrap()
+                .prime(),
+            Some(true)
+        );
+    }
+
+    fn engine(args: &[&str]) -> Engine {
+        use clap::Parser;
+        let args = std::iter::once("prime_time").chain(args.iter().copied());
+        Engine::new(&Config::parse_from(args), None)
+    }
+
+    async fn process_line(
+        engine: &Engine,
+        line: &[u8],
+        token: &CancellationToken,
+    ) -> Result<Response, Error> {
+        engine.process(Request::parse(line)?, token).await
+    }
+
+    #[tokio::test]
+    async fn json_rpc_only_when_asked() {
+        let token = CancellationToken::new();
+        let plain = engine(&[]);
+        for line in [&b"[]"[..], b"[1,2]"] {
+            assert!(matches!(
+                plain.answer(line, &token).await,
+                Err(Error::NotAnObject)
+            ));
+        }
+        let line = br#"{"method":"isPrime","number":7,"jsonrpc":"x"}"#;
+        assert_eq!(
+            plain.answer(line, &token).await.unwrap(),
+            b"{\"method\":\"isPrime\",\"prime\":true}\n"
+        );
+        let rpc = engine(&["--json-rpc"]);
+        assert!(rpc.answer(b"[1,2]", &token).await.is_ok());
+    }
+
+    async fn answer_json(engine: &Engine, line: &str) -> serde_json::Value {
+        let answer = engine
+            .answer(line.as_bytes(), &CancellationToken::new())
+            .await
+            .unwrap();
+        serde_json::from_slice(&answer).unwrap()
+    }
+
+    #[tokio::test]
+    async fn huge_numbers() {
+        let engine = engine(&[]);
+        let prime = answer_json(&engine, r#"{"method":"isPrime","number":1e100000}"#).await;
+        assert_eq!(prime["prime"], false);
+        let line = r#"{"method":"isPrimeMany","numbers":[7,-3e20000]}"#;
+        let primes = answer_json(&engine, line).await;
+        assert_eq!(primes["primes"], serde_json::json!([true, false]));
+        // The rest need the value itself, so the request is bad, but the
+        // lines after it are fine
+        for method in ["factorize", "nextPrime", "prevPrime", "certifyPrime"] {
+            let line = format!(r#"{{"method":"{method}","number":1e100000}}"#);
+            let refused = process_line(&engine, line.as_bytes(), &CancellationToken::new())
+                .await
+                .unwrap_err();
+            assert_eq!(refused.code(), "number_too_large");
+            assert!(refused.is_recoverable());
+            assert!(refused.to_string().contains("1e100000"), "{refused}");
+        }
+    }
+
+    #[tokio::test]
+    async fn offloaded_requests() {
+        let engine = engine(&["--offload-bits", "0", "--compute-threads", "2"]);
+        let token = CancellationToken::new();
+        let line = br#"{"method":"isPrime","number":170141183460469231731687303715884105727}"#;
+        assert_eq!(
+            process_line(&engine, line, &token).await.unwrap().prime(),
+            Some(true)
+        );
+        let line = br#"{"method":"isPrime","number":2.5}"#;
+        assert_eq!(
+            process_line(&engine, line, &token).await.unwrap().prime(),
+            Some(false)
+        );
+    }
+
+    #[tokio::test]
+    async fn cancelled_requests() {
+        let engine = engine(&["--offload-bits", "0", "--request-budget-ms", "0"]);
+        let token = CancellationToken::new();
+        token.cancel();
+        let m = (rug::Integer::from(1) << 9689u32) - 1;
+        let line = format!(r#"{{"method":"isPrime","number":{m}}}"#);
+        assert!(matches!(
+            process_line(&engine, line.as_bytes(), &token).await,
+            Err(Error::Interrupted(Interrupted::Cancelled))
+        ));
+    }
+
+    #[tokio::test]
+    async fn budget_exceeded() {
+        let engine = engine(&["--offload-bits", "0", "--request-budget-ms", "1"]);
+        let m = (rug::Integer::from(1) << 9941u32) - 1;
+        let line = format!(r#"{{"method":"isPrime","number":{m}}}"#);
+        assert!(matches!(
+            process_line(&engine, line.as_bytes(), &CancellationToken::new()).await,
+            Err(Error::Interrupted(Interrupted::OutOfTime))
+        ));
+        // Giving up is not an answer worth remembering
+        let stats = engine.cache_stats().unwrap();
+        assert_eq!((stats.entries, stats.pinned), (0, 0));
+    }
+
+    /// Answers `line` with the default settings
+    async fn process(line: &[u8]) -> Result<Response, Error> {
+        process_line(&engine(&[]), line, &CancellationToken::new()).await
+    }
+
+    async fn answer(line: &str) -> String {
+        let response = process(line.as_bytes()).await.unwrap();
+        String::from_utf8(response.serialize().collect()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn factorize() {
+        assert_eq!(
+            answer(r#"{"method":"factorize","number":-360}"#).await,
+            "{\"method\":\"factorize\",\"factors\":[[-1,1],[2,3],[3,2],[5,1]]}\n"
+        );
+        assert_eq!(
+            answer(r#"{"method":"factorize","number":1}"#).await,
+            "{\"method\":\"factorize\",\"factors\":[]}\n"
+        );
+        assert_eq!(
+            answer(r#"{"method":"factorize","number":18446744073709551617}"#).await,
+            "{\"method\":\"factorize\",\"factors\":[[274177,1],[67280421310721,1]]}\n"
+        );
+        assert!(matches!(
+            process(br#"{"method":"factorize","number":2.5}"#).await,
+            Err(Error::NotAnInteger { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn prime_queries() {
+        for (line, expected) in [
+            (
+                r#"{"method":"nextPrime","number":-10}"#,
+                r#"{"method":"nextPrime","prime":2}"#,
+            ),
+            (
+                r#"{"method":"nextPrime","number":1e20}"#,
+                r#"{"method":"nextPrime","prime":100000000000000000039}"#,
+            ),
+            (
+                r#"{"method":"prevPrime","number":1e20}"#,
+                r#"{"method":"prevPrime","prime":99999999999999999989}"#,
+            ),
+            (
+                r#"{"method":"nthPrime","number":1e6}"#,
+                r#"{"method":"nthPrime","prime":15485863}"#,
+            ),
+            (
+                r#"{"method":"primeCount","number":-7}"#,
+                r#"{"method":"primeCount","count":0}"#,
+            ),
+            (
+                r#"{"method":"primeCount","number":1e9}"#,
+                r#"{"method":"primeCount","count":50847534}"#,
+            ),
+        ] {
+            assert_eq!(answer(line).await, format!("{expected}\n"));
+        }
+        for line in [
+            r#"{"method":"prevPrime","number":2}"#,
+            r#"{"method":"nthPrime","number":0}"#,
+            r#"{"method":"nthPrime","number":1e11}"#,
+            r#"{"method":"primeCount","number":1e13}"#,
+        ] {
+            assert!(
+                matches!(
+                    process(line.as_bytes()).await,
+                    Err(Error::OutOfRange { .. })
+                ),
+                "{line}"
+            );
+        }
+        assert!(matches!(
+            process(br#"{"method":"nextPrime","number":0.5}"#).await,
+            Err(Error::NotAnInteger { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn batches() {
+        assert_eq!(
+            answer(r#"{"method":"isPrimeMany","numbers":[2,4,7.0,7.5,-7,1e2,97]}"#).await,
+            "{\"method\":\"isPrimeMany\",\"primes\":[true,false,true,false,false,false,true]}\n"
+        );
+        assert_eq!(
+            answer(r#"{"method":"isPrimeMany","numbers":[]}"#).await,
+            "{\"method\":\"isPrimeMany\",\"primes\":[]}\n"
+        );
+        let parse = |line: &[u8]| Request::parse(line).unwrap_err();
+        assert!(matches!(
+            parse(br#"{"method":"isPrimeMany","number":7}"#),
+            Error::MissingNumbers
+        ));
+        assert!(matches!(
+            parse(br#"{"method":"isPrimeMany","numbers":7}"#),
+            Error::NumbersNotArray(_)
+        ));
+        assert!(matches!(
+            parse(br#"{"method":"isPrimeMany","numbers":[2,3,"5",7]}"#),
+            Error::InBatch { index: 2, source } if matches!(*source, Error::NumberIsString(_))
+        ));
+        let too_many = format!(
+            r#"{{"method":"isPrimeMany","numbers":[{}]}}"#,
+            vec!["1"; MAX_BATCH + 1].join(",")
+        );
+        assert!(matches!(
+            parse(too_many.as_bytes()),
+            Error::TooManyNumbers(n) if n == MAX_BATCH + 1
+        ));
+    }
+
+    #[tokio::test]
+    async fn certificates() {
+        assert_eq!(
+            answer(r#"{"method":"certifyPrime","number":91}"#).await,
+            "{\"method\":\"certifyPrime\",\"prime\":false}\n"
+        );
+        assert_eq!(
+            answer(r#"{"method":"certifyPrime","number":97}"#).await,
+            "{\"method\":\"certifyPrime\",\"certificate\":{\"small\":97}}\n"
+        );
+        // What comes back checks out on its own
+        let line = r#"{"method":"certifyPrime","number":170141183460469231731687303715884105727}"#;
+        let response: serde_json::Value = serde_json::from_str(&answer(line).await).unwrap();
+        let certificate: Certificate =
+            serde_json::from_value(response["certificate"].clone()).unwrap();
+        assert_eq!(certificate.n(), &((rug::Integer::from(1) << 127u32) - 1));
+        assert_eq!(certificate.verify(), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn factorize_time_cap() {
+        let engine = engine(&["--request-budget-ms", "10"]);
+        let p = (rug::Integer::from(1) << 89u32) - 1;
+        let q = (rug::Integer::from(1) << 107u32) - 1;
+        let line = format!(r#"{{"method":"factorize","number":{}}}"#, p * q);
+        let response = process_line(&engine, line.as_bytes(), &CancellationToken::new())
+            .await
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(response.serialize().collect()).unwrap(),
+            "{\"method\":\"factorize\",\"error\":\"Ran out of time\"}\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn answers_are_cached() {
+        let cached = engine(&["--cache-pin-below", "100"]);
+        let token = CancellationToken::new();
+        for line in [
+            &br#"{"method":"isPrime","number":97}"#[..],
+            br#"{"method":"isPrime","number":9.7e1}"#,
+            br#"{"method":"isPrime","number":1000}"#,
+            br#"{"method":"isPrime","number":1e3}"#,
+            br#"{"method":"isPrime","number":1}"#,
+        ] {
+            process_line(&cached, line, &token).await.unwrap();
+        }
+        let stats = cached.cache_stats().unwrap();
+        assert_eq!((stats.hits, stats.misses), (2, 2));
+        assert_eq!((stats.entries, stats.pinned), (1, 1));
+
+        assert!(engine(&["--cache-capacity", "0"]).cache_stats().is_none());
+    }
+
     proptest! {
 
         #[test]
         fn is_prime_equivalence(x in -10000_i64..10000_i64) {
-            //prop_assert_eq!(is_prime(x), super::is_prime_opt(x));
+            prop_assert_eq!(is_prime(x), primality::is_prime(&x.into(), &Budget::unlimited()).unwrap());
         }
 
 
@@ -189,23 +924,5 @@ mod test {
             let response_str = String::from_utf8(response_buf.collect()).unwrap();
             prop_assert!(response_str.ends_with('\n'));
         }
-
-        #[test]
-        fn every_request_processed(requests in requests()) {
-            let s = requests.iter().map(|r| serde_json::to_string(r).unwrap()).collect::<Vec<_>>().join("\n");
-            let result = process_requests_(s.lines());
-            prop_assert_eq!(requests.len(), result.responses.len());
-            prop_assert!(result.ok);
-        }
-
-        #[test]
-        fn up_to_malformed(m in malformed()) {
-            let result = process_requests_(m.buf.lines());
-            prop_assert_eq!(result.responses.len(), m.before_malformed);
-            prop_assert!(!result.ok);
-        }
-
-
-
     }
 }
@@ -0,0 +1,163 @@
+//! Primality by trial division, proven by Verus to agree with the definition
+//! of a prime.
+//!
+//! Verus checks this module as it is. Every other build goes through
+//! `verus_erase`, which drops the specs and proofs, so what runs is exactly
+//! what was proven.
+
+// Verus knows `%`, but not `is_multiple_of`
+#![allow(clippy::manual_is_multiple_of)]
+
+#[cfg(not(verus_keep_ghost))]
+use verus_erase::verus;
+#[cfg(verus_keep_ghost)]
+use vstd::arithmetic::div_mod::{lemma_fundamental_div_mod, lemma_mod_multiples_basic};
+#[cfg(verus_keep_ghost)]
+use vstd::arithmetic::mul::lemma_mul_upper_bound;
+#[cfg(verus_keep_ghost)]
+use vstd::prelude::*;
+
+verus! {
+
+    pub open spec fn is_prime_nat(n : nat) -> bool {
+        &&& forall |i : nat| 1 < i < n ==> (#[trigger] (n % i)) != 0
+        &&& n != 0
+        &&& n != 1
+    }
+
+    proof fn bv_to_nat_modulo(n : u64, i : u64)
+            requires i < n && i != 0
+            ensures (n % i) as nat == (n as nat) % (i as nat)
+            {}
+
+    // The first version, linear in `n`, kept for its proof
+    #[allow(dead_code)]
+    pub fn is_prime_impl(n : u64)  -> (r : bool)
+        ensures r == is_prime_nat(n as nat)
+    {
+        if n <= 1 {
+            return false;
+        }
+        let mut i = 2;
+        while i < n
+            invariant
+                2 <= i <= n
+                && forall |j:nat| 1 < j < i ==> (#[trigger] (n as nat % j)) != 0
+            decreases
+                n - i
+        {
+            if n % i == 0 {
+                assert((n as nat) % (i as nat) == 0);
+                return false;
+            }
+            i += 1;
+        }
+        assert(is_prime_nat(n as nat));
+        true
+    }
+
+    spec fn is_square_root(n : nat, x : nat) -> bool {
+        forall |j : nat| (#[trigger] (j * j)) <= n ==> j <= x
+    }
+
+    proof fn square_root_is_enough(n : nat, x : nat)
+        requires
+            n > 1,
+            is_square_root(n, x),
+            forall |i : nat| 1 < i <= x ==> (#[trigger] (n % i)) != 0
+        ensures
+            is_prime_nat(n)
+    {
+        assert forall |i : nat| 1 < i < n implies (#[trigger] (n % i)) != 0 by {
+            if n % i == 0 {
+                // n = i * q, and whichever of i and q is smaller is at most x
+                let q = n / i;
+                lemma_fundamental_div_mod(n as int, i as int);
+                assert(n == i * q);
+                if i * i <= n {
+                    assert(i <= x);
+                } else {
+                    assert(q < i) by (nonlinear_arith)
+                        requires n == i * q, i * i > n;
+                    assert(q * q <= n) by (nonlinear_arith)
+                        requires n == i * q, q < i;
+                    assert(q <= x);
+                    assert(1 < q) by (nonlinear_arith)
+                        requires n == i * q, i < n;
+                    lemma_mod_multiples_basic(i as int, q as int);
+                    assert(n % q == 0);
+                }
+            }
+        }
+    }
+
+    pub fn is_prime_sqrt(n : u64) -> (r : bool)
+        ensures r == is_prime_nat(n as nat)
+    {
+        if n <= 1 {
+            return false;
+        }
+        let mut i : u64 = 2;
+        // Squared in u128, which can't overflow as i fits in a u64
+        while (i as u128) * (i as u128) <= n as u128
+            invariant
+                2 <= i <= n
+                && (i as int) * (i as int) <= u128::MAX
+                && forall |j:nat| 1 < j < i ==> (#[trigger] (n as nat % j)) != 0
+            decreases
+                n - i
+        {
+            if n % i == 0 {
+                assert((n as nat) % (i as nat) == 0);
+                return false;
+            }
+            assert(i + 1 <= n) by (nonlinear_arith)
+                requires 2 <= i, (i as int) * (i as int) <= n as int;
+            proof {
+                let next = i as int + 1;
+                lemma_mul_upper_bound(next, u64::MAX as int, next, u64::MAX as int);
+                assert((u64::MAX as int) * (u64::MAX as int) <= u128::MAX);
+            }
+            i += 1;
+        }
+        proof {
+            let x = (i - 1) as nat;
+            assert forall |j : nat| (#[trigger] (j * j)) <= n implies j <= x by {
+                if j > x {
+                    assert(j * j >= i * i) by (nonlinear_arith)
+                        requires j >= i, i >= 0;
+                }
+            }
+            square_root_is_enough(n as nat, x);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::primality::{VERIFIED_BELOW, is_prime_u64};
+    use proptest::prelude::*;
+
+    #[test]
+    fn sqrt_stops_at_the_root() {
+        for n in 0..10_000 {
+            assert_eq!(is_prime_sqrt(n), is_prime_u64(n), "{n}");
+            assert_eq!(is_prime_impl(n), is_prime_u64(n), "{n}");
+        }
+        // Squares of primes only have their root to give them away
+        for p in [2u64, 3, 65_521, 65_537] {
+            assert!(is_prime_sqrt(p));
+            assert!(!is_prime_sqrt(p * p), "{p}");
+        }
+        assert!(is_prime_sqrt(VERIFIED_BELOW - 5));
+    }
+
+    proptest! {
+        #[test]
+        fn sqrt_agrees_with_miller_rabin(n in 0..VERIFIED_BELOW) {
+            prop_assert_eq!(is_prime_sqrt(n), is_prime_u64(n));
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! `verus!` for builds without Verus: drops the specs and proofs, and leaves
+//! the executable code exactly as Verus checked it.
+//!
+//! Only the syntax prime_time uses is understood: `spec` and `proof`
+//! functions, `requires`/`ensures`/`recommends`/`decreases` clauses, named
+//! return values, loop `invariant`s, `assert` statements and `proof` blocks.
+
+use proc_macro::{Delimiter, Group, Spacing, TokenStream, TokenTree};
+use std::iter::Peekable;
+
+/// Clauses between a function's signature and its body
+const FN_CLAUSES: &[&str] = &["requires", "ensures", "recommends", "decreases"];
+
+/// Clauses between a loop's condition and its body
+const LOOP_CLAUSES: &[&str] = &[
+    "invariant",
+    "invariant_except_break",
+    "ensures",
+    "decreases",
+];
+
+#[proc_macro]
+pub fn verus(input: TokenStream) -> TokenStream {
+    let mut out = vec![];
+    let mut tokens = input.into_iter().peekable();
+    while tokens.peek().is_some() {
+        let item = next_item(&mut tokens);
+        match header(&item) {
+            Some(header) if header.iter().any(|i| i == "spec" || i == "proof") => {}
+            Some(_) => out.extend(function(item)),
+            None => out.extend(item),
+        }
+    }
+    out.into_iter().collect()
+}
+
+/// Everything up to and including the `;` or the block that ends an item
+fn next_item(tokens: &mut Peekable<impl Iterator<Item = TokenTree>>) -> Vec<TokenTree> {
+    let mut item = vec![];
+    for token in tokens.by_ref() {
+        let end = is_punct(&token, ';') || is_block(&token);
+        item.push(token);
+        if end {
+            break;
+        }
+    }
+    item
+}
+
+/// The words before `fn`, if `item` is a function
+fn header(item: &[TokenTree]) -> Option<Vec<String>> {
+    let mut words = vec![];
+    for token in item {
+        if let TokenTree::Ident(ident) = token {
+            let word = ident.to_string();
+            if word == "fn" {
+                return Some(words);
+            }
+            words.push(word);
+        }
+    }
+    None
+}
+
+/// An executable function, without its clauses and with a plain return type
+fn function(item: Vec<TokenTree>) -> Vec<TokenTree> {
+    let mut out = vec![];
+    let mut tokens = item.into_iter().peekable();
+    while let Some(token) = tokens.next() {
+        match &token {
+            TokenTree::Ident(ident) if FN_CLAUSES.contains(&&*ident.to_string()) => {
+                while tokens.peek().is_some_and(|t| !is_block(t)) {
+                    tokens.next();
+                }
+            }
+            TokenTree::Punct(p) if p.as_char() == '>' && is_arrow(&out) => {
+                out.push(token);
+                if let Some(TokenTree::Group(group)) = tokens.peek()
+                    && group.delimiter() == Delimiter::Parenthesis
+                    && let Some(ty) = named_return(group.stream())
+                {
+                    out.extend(ty);
+                    tokens.next();
+                }
+            }
+            TokenTree::Group(group) if group.delimiter() == Delimiter::Brace => {
+                out.push(block(group));
+            }
+            _ => out.push(token),
+        }
+    }
+    out
+}
+
+/// The type in `(name: Type)`, or `None` for a tuple
+fn named_return(stream: TokenStream) -> Option<Vec<TokenTree>> {
+    let tokens: Vec<_> = stream.into_iter().collect();
+    match &tokens[..] {
+        [TokenTree::Ident(_), TokenTree::Punct(colon), ty @ ..]
+            if colon.as_char() == ':' && colon.spacing() == Spacing::Alone =>
+        {
+            Some(ty.to_vec())
+        }
+        _ => None,
+    }
+}
+
+/// A block without its ghost statements, and with loops stripped of their
+/// clauses
+fn block(group: &Group) -> TokenTree {
+    let mut out = vec![];
+    let mut tokens = group.stream().into_iter().peekable();
+    while let Some(token) = tokens.next() {
+        match &token {
+            TokenTree::Ident(ident)
+                if ident.to_string() == "assert" && is_ghost_assert(&mut tokens) =>
+            {
+                skip_assert(&mut tokens);
+            }
+            TokenTree::Ident(ident)
+                if ident.to_string() == "proof" && tokens.peek().is_some_and(is_block) =>
+            {
+                tokens.next();
+            }
+            TokenTree::Ident(ident) if LOOP_CLAUSES.contains(&&*ident.to_string()) => {
+                while tokens.peek().is_some_and(|t| !is_block(t)) {
+                    tokens.next();
+                }
+            }
+            TokenTree::Group(group) if group.delimiter() == Delimiter::Brace => {
+                out.push(block(group));
+            }
+            _ => out.push(token),
+        }
+    }
+    let mut erased = Group::new(group.delimiter(), out.into_iter().collect());
+    erased.set_span(group.span());
+    TokenTree::Group(erased)
+}
+
+/// Whether an `assert` is Verus's rather than a call to `assert!`
+fn is_ghost_assert(tokens: &mut Peekable<impl Iterator<Item = TokenTree>>) -> bool {
+    match tokens.peek() {
+        Some(TokenTree::Group(group)) => group.delimiter() == Delimiter::Parenthesis,
+        Some(TokenTree::Ident(ident)) => ident.to_string() == "forall",
+        _ => false,
+    }
+}
+
+/// Skips the rest of an `assert`, which ends at a `;` or at the block of its
+/// `by`
+fn skip_assert(tokens: &mut Peekable<impl Iterator<Item = TokenTree>>) {
+    let mut after_by = false;
+    for token in tokens.by_ref() {
+        if is_punct(&token, ';') {
+            return;
+        }
+        if after_by && is_block(&token) {
+            break;
+        }
+        after_by = matches!(&token, TokenTree::Ident(ident) if ident.to_string() == "by");
+    }
+    if tokens.peek().is_some_and(|t| is_punct(t, ';')) {
+        tokens.next();
+    }
+}
+
+fn is_punct(token: &TokenTree, c: char) -> bool {
+    matches!(token, TokenTree::Punct(p) if p.as_char() == c)
+}
+
+fn is_block(token: &TokenTree) -> bool {
+    matches!(token, TokenTree::Group(g) if g.delimiter() == Delimiter::Brace)
+}
+
+/// Whether `tokens` ends in the `-` of a `->`
+fn is_arrow(tokens: &[TokenTree]) -> bool {
+    matches!(tokens.last(), Some(TokenTree::Punct(p)) if p.as_char() == '-' && p.spacing() == Spacing::Joint)
+}
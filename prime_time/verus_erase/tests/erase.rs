@@ -0,0 +1,134 @@
+//! Each construct `verus!` strips, written with syntax only Verus accepts, so
+//! any of it left behind fails the build. What's left has to run as written.
+
+use verus_erase::verus;
+
+verus! {
+
+    pub open spec fn is_even(n: nat) -> bool {
+        &&& n % 2 == 0
+        &&& forall |i: nat| i < n ==> i * 2 != n + 1
+    }
+
+    spec fn closed_spec(n: nat) -> nat recommends n > 0 { (n - 1) as nat }
+
+    proof fn lemma_even(n: nat)
+        requires n % 2 == 0
+        ensures is_even(n) || !is_even(n)
+        decreases n
+    {
+        assert(is_even(n) ==> n % 2 == 0);
+    }
+
+    pub proof fn lemma_public(n: nat) ensures n + 0 == n {}
+
+    /// Items that aren't functions go through untouched
+    pub const LIMIT: u64 = 100;
+
+    pub struct Pair(pub u64, pub u64);
+
+    pub fn clauses(n: u64) -> u64
+        requires n < 100
+        ensures n < 200
+        recommends n > 0
+        decreases n
+    {
+        n + 1
+    }
+
+    pub fn named_return(n: u64) -> (r: u64)
+        ensures r == n * 2
+    {
+        n * 2
+    }
+
+    pub fn tuple_return(n: u64) -> (u64, bool) {
+        (n, n > 10)
+    }
+
+    pub fn loops(n: u64) -> (total: u64)
+        requires n <= LIMIT
+    {
+        let mut i = 0;
+        let mut total = 0;
+        while i < n
+            invariant
+                i <= n,
+                total == i * (i - 1) / 2
+            invariant_except_break total >= 0
+            ensures i == n
+            decreases n - i
+        {
+            total += i;
+            i += 1;
+        }
+        total
+    }
+
+    pub fn asserts(n: u64) -> u64 {
+        assert(n as nat >= 0nat);
+        assert(n * n >= 0) by (nonlinear_arith)
+            requires n >= 0;
+        assert forall |i: nat| i < n implies #[trigger] (i + 1) <= n by {
+            assert(i < n);
+        }
+        // The one assert that runs
+        assert!(n < LIMIT, "too big");
+        n
+    }
+
+    pub fn proof_blocks(n: u64) -> u64 {
+        let proof = n + 1;
+        proof {
+            lemma_even(2nat);
+            let ghost m: nat = n as nat;
+            assert(is_even(2 * m));
+        }
+        if n > 0 {
+            proof { lemma_public(n as nat); }
+            return proof;
+        }
+        proof
+    }
+}
+
+#[test]
+fn clauses_are_dropped() {
+    assert_eq!(clauses(41), 42);
+}
+
+#[test]
+fn named_returns_are_plain_types() {
+    let doubled: u64 = named_return(21);
+    assert_eq!(doubled, 42);
+    assert_eq!(tuple_return(4), (4, false));
+}
+
+#[test]
+fn loops_keep_their_bodies() {
+    assert_eq!(loops(10), 45);
+    assert_eq!(loops(0), 0);
+}
+
+#[test]
+fn ghost_asserts_are_dropped() {
+    assert_eq!(asserts(7), 7);
+}
+
+#[test]
+#[should_panic(expected = "too big")]
+fn assert_macros_stay() {
+    asserts(LIMIT);
+}
+
+#[test]
+fn proof_blocks_are_dropped() {
+    assert_eq!(proof_blocks(0), 1);
+    assert_eq!(proof_blocks(5), 6);
+}
+
+#[test]
+fn other_items_stay() {
+    let Pair(a, b) = Pair(LIMIT, 1);
+    assert_eq!(a + b, 101);
+}
@@ -0,0 +1,656 @@
+use crate::client::{self, Client, Reply};
+use crate::verify::{self, Discrepancy};
+use crate::{Request, Response};
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::fmt;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, mpsc};
+use tokio::task::{self, JoinHandle};
+use tracing::{error, warn};
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// File of numbers, one per line, or `-` for stdin
+    #[arg(default_value = "-")]
+    pub input: PathBuf,
+
+    /// File to write the results to, instead of stdout
+    #[arg(long, short)]
+    pub output: Option<PathBuf>,
+
+    #[arg(long, value_enum, default_value_t = Format::Csv)]
+    pub format: Format,
+
+    #[command(flatten)]
+    pub verify: verify::Args,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// `<number> is prime`, skipping anything that failed
+    Text,
+    /// `number,verdict,latency_ms` with a header
+    Csv,
+    /// One JSON object per number
+    Jsonl,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Verdict {
+    Prime,
+    NotPrime,
+    Error,
+}
+
+impl Verdict {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Prime => "prime",
+            Self::NotPrime => "not_prime",
+            Self::Error => "error",
+        }
+    }
+}
+
+/// A line of input, in the order it was read
+enum Pending {
+    Sent {
+        number: serde_json::Number,
+        answer: client::Answer,
+        sent: Instant,
+        /// The local test, when verifying
+        local: Option<JoinHandle<Option<bool>>>,
+        /// Its place in the window, given back once answered and checked
+        permit: OwnedSemaphorePermit,
+    },
+    /// Not a number, so never sent
+    Unparsed(String),
+}
+
+/// A line of input with its answer, and whatever's left to check it against
+struct Answered {
+    row: Row,
+    checks: Option<Checks>,
+}
+
+struct Checks {
+    /// What the server answered to `isPrime`
+    response: String,
+    local: Option<JoinHandle<Option<bool>>>,
+    /// The `certifyPrime` request, if one went out
+    certified: Option<Result<client::Answer, client::Error>>,
+    _permit: OwnedSemaphorePermit,
+}
+
+/// The outcome for one line of input
+struct Row {
+    number: Result<serde_json::Number, String>,
+    verdict: Verdict,
+    latency: Option<Duration>,
+    discrepancies: Vec<Discrepancy>,
+}
+
+/// How a run went
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub count: usize,
+    pub primes: usize,
+    pub errors: usize,
+    /// Answers the local checks disagreed with, if there were any checks
+    pub disagreements: Option<usize>,
+    pub elapsed: Duration,
+}
+
+impl Summary {
+    /// Numbers answered per second
+    pub fn throughput(&self) -> f64 {
+        self.count as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} numbers, {} prime, {} errors in {:.3?} ({:.0} numbers/s)",
+            self.count,
+            self.primes,
+            self.errors,
+            self.elapsed,
+            self.throughput()
+        )?;
+        if let Some(disagreements) = self.disagreements {
+            write!(f, ", {disagreements} disagreements")?;
+        }
+        Ok(())
+    }
+}
+
+/// Asks about every number in `input`, keeping up to `max_in_flight` requests
+/// on the wire, and writes the answers to `output` in the order they were
+/// asked. With `verify` every number is tested locally too, and with a
+/// `certifier` every number the server calls prime is certified over that
+/// second connection. Any disagreements go to stderr.
+pub async fn run<R, W>(
+    client: &Client,
+    certifier: Option<&Client>,
+    input: R,
+    output: W,
+    format: Format,
+    max_in_flight: usize,
+    verify: bool,
+) -> Result<Summary>
+where
+    R: AsyncRead + Unpin,
+    W: Write,
+{
+    let start = Instant::now();
+    let checked = verify || certifier.is_some();
+    // Set once the certifier turns out not to take certifyPrime
+    let uncertifiable = AtomicBool::new(false);
+    let (pending_tx, pending_rx) = mpsc::unbounded_channel();
+    let (answered_tx, answered_rx) = mpsc::unbounded_channel();
+    let (sent, answered, summary) = tokio::join!(
+        send_all(client, input, pending_tx, max_in_flight, verify),
+        answer_all(pending_rx, answered_tx, certifier, &uncertifiable),
+        write_all(answered_rx, output, format, checked, &uncertifiable)
+    );
+    sent?;
+    answered?;
+    let mut summary = summary?;
+    client.finish().await?;
+    if let Some(certifier) = certifier {
+        certifier.finish().await?;
+    }
+    summary.elapsed = start.elapsed();
+    Ok(summary)
+}
+
+async fn send_all<R: AsyncRead + Unpin>(
+    client: &Client,
+    input: R,
+    pending: mpsc::UnboundedSender<Pending>,
+    max_in_flight: usize,
+    verify: bool,
+) -> Result<()> {
+    let window = Arc::new(Semaphore::new(max_in_flight.max(1)));
+    let mut lines = BufReader::new(input).lines();
+    while let Some(line) = lines.next_line().await? {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        // Any JSON number, kept exactly as written
+        let Ok(number) = line.parse::<serde_json::Number>() else {
+            error!("Couldn't parse number: {line}");
+            pending.send(Pending::Unparsed(line.to_string()))?;
+            continue;
+        };
+        let permit = window.clone().acquire_owned().await?;
+        let request = Request {
+            method: "isPrime",
+            number: number.clone(),
+        };
+        let sent = Instant::now();
+        let answer = client.send(&request).await?;
+        let local = verify.then(|| {
+            let number = number.clone();
+            task::spawn_blocking(move || verify::is_prime(&number))
+        });
+        pending.send(Pending::Sent {
+            number,
+            answer,
+            sent,
+            local,
+            permit,
+        })?;
+    }
+    Ok(())
+}
+
+/// Takes the answers as they come in, asking for each certificate as soon as
+/// the server has called a number prime, without waiting for the last one
+async fn answer_all(
+    mut pending: mpsc::UnboundedReceiver<Pending>,
+    answered: mpsc::UnboundedSender<Answered>,
+    certifier: Option<&Client>,
+    uncertifiable: &AtomicBool,
+) -> Result<()> {
+    while let Some(pending) = pending.recv().await {
+        let certifier = certifier.filter(|_| !uncertifiable.load(Ordering::Relaxed));
+        answered.send(answer(pending, certifier).await)?;
+    }
+    Ok(())
+}
+
+/// Waits for the answer to `pending`, and sends off for a certificate if
+/// there's a `certifier` and something to certify
+async fn answer(pending: Pending, certifier: Option<&Client>) -> Answered {
+    let (number, answer, sent, local, permit) = match pending {
+        Pending::Sent {
+            number,
+            answer,
+            sent,
+            local,
+            permit,
+        } => (number, answer, sent, local, permit),
+        Pending::Unparsed(line) => {
+            let row = Row {
+                number: Err(line),
+                verdict: Verdict::Error,
+                latency: None,
+                discrepancies: vec![],
+            };
+            return Answered { row, checks: None };
+        }
+    };
+    let answer = answer.await;
+    let verdict = match &answer {
+        Ok(Ok(Reply { line, .. })) => match serde_json::from_str::<Response>(line) {
+            Ok(response) if response.method == "isPrime" => {
+                if response.prime {
+                    Verdict::Prime
+                } else {
+                    Verdict::NotPrime
+                }
+            }
+            Ok(response) => {
+                error!("Invalid response method! ({})", response.method);
+                Verdict::Error
+            }
+            Err(_) => {
+                error!("Invalid response for {number}: {line}");
+                Verdict::Error
+            }
+        },
+        Ok(Err(e)) => {
+            error!("No answer for {number}: {e}");
+            Verdict::Error
+        }
+        Err(_) => Verdict::Error,
+    };
+    let certifier = certifier.filter(|_| verdict == Verdict::Prime && verify::certifiable(&number));
+    let mut row = Row {
+        number: Ok(number),
+        verdict,
+        latency: None,
+        discrepancies: vec![],
+    };
+    let Ok(Ok(Reply { line, received })) = answer else {
+        return Answered { row, checks: None };
+    };
+    if verdict == Verdict::Error {
+        return Answered { row, checks: None };
+    }
+    // Timed by when the answer arrived rather than when it got looked at,
+    // which can be a lot later
+    row.latency = Some(received.duration_since(sent));
+    if local.is_none() && certifier.is_none() {
+        return Answered { row, checks: None };
+    }
+    let certified = match (certifier, &row.number) {
+        (Some(certifier), Ok(number)) => {
+            let request = Request {
+                method: "certifyPrime",
+                number: number.clone(),
+            };
+            Some(certifier.send(&request).await)
+        }
+        _ => None,
+    };
+    let checks = Checks {
+        response: line,
+        local,
+        certified,
+        _permit: permit,
+    };
+    Answered {
+        row,
+        checks: Some(checks),
+    }
+}
+
+async fn write_all<W: Write>(
+    mut answered: mpsc::UnboundedReceiver<Answered>,
+    output: W,
+    format: Format,
+    checked: bool,
+    uncertifiable: &AtomicBool,
+) -> Result<Summary> {
+    let mut output = Output::new(output, format)?;
+    let mut summary = Summary {
+        disagreements: checked.then_some(0),
+        ..Summary::default()
+    };
+    while let Some(Answered { mut row, checks }) = answered.recv().await {
+        if let (Some(checks), Ok(number)) = (checks, &row.number) {
+            row.discrepancies = check(number, row.verdict, checks, uncertifiable).await;
+        }
+        summary.count += 1;
+        match row.verdict {
+            Verdict::Prime => summary.primes += 1,
+            Verdict::NotPrime => {}
+            Verdict::Error => summary.errors += 1,
+        }
+        for discrepancy in &row.discrepancies {
+            eprintln!("{discrepancy}");
+        }
+        if let Some(disagreements) = &mut summary.disagreements {
+            *disagreements += row.discrepancies.len();
+        }
+        output.write(&row)?;
+    }
+    output.flush()?;
+    Ok(summary)
+}
+
+/// Checks the server's `verdict` on `number` against the local test and
+/// the certificate, once they're in
+async fn check(
+    number: &serde_json::Number,
+    verdict: Verdict,
+    checks: Checks,
+    uncertifiable: &AtomicBool,
+) -> Vec<Discrepancy> {
+    let local = match checks.local {
+        Some(local) => local.await.unwrap_or_default(),
+        None => None,
+    };
+    let certified = match checks.certified {
+        Some(sent) => certificate(sent, uncertifiable).await,
+        None => None,
+    };
+    if local.is_none() && certified.is_none() {
+        return vec![];
+    }
+    let number = number.clone();
+    let prime = verdict == Verdict::Prime;
+    let response = checks.response;
+    // Checking a certificate can take a while
+    let checked =
+        task::spawn_blocking(move || verify::check(&number, prime, &response, local, certified));
+    checked.await.unwrap_or_default()
+}
+
+/// The answer to a `certifyPrime` request, unless the server doesn't take
+/// them. A server that doesn't calls the request malformed and hangs up, so
+/// nothing more is certified once that happens.
+async fn certificate(
+    sent: Result<client::Answer, client::Error>,
+    uncertifiable: &AtomicBool,
+) -> Option<String> {
+    let answer = match sent {
+        Ok(answer) => answer.await.unwrap_or(Err(client::Error::Closed)),
+        Err(e) => Err(e),
+    };
+    match answer {
+        Ok(reply) if verify::answers_certify(&reply.line) => Some(reply.line),
+        answer => {
+            if !uncertifiable.swap(true, Ordering::Relaxed) {
+                let why = match answer {
+                    Ok(reply) => reply.line,
+                    Err(e) => e.to_string(),
+                };
+                warn!("Not certifying any more numbers, as certifyPrime failed: {why}");
+            }
+            None
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonRow {
+    number: serde_json::Value,
+    verdict: Verdict,
+    latency_ms: Option<f64>,
+}
+
+enum Output<W: Write> {
+    Text(W),
+    Csv(Box<csv::Writer<W>>),
+    Jsonl(W),
+}
+
+impl<W: Write> Output<W> {
+    fn new(output: W, format: Format) -> Result<Self> {
+        Ok(match format {
+            Format::Text => Self::Text(output),
+            Format::Csv => {
+                let mut csv = csv::Writer::from_writer(output);
+                csv.write_record(["number", "verdict", "latency_ms"])?;
+                Self::Csv(Box::new(csv))
+            }
+            Format::Jsonl => Self::Jsonl(output),
+        })
+    }
+
+    fn write(&mut self, row: &Row) -> Result<()> {
+        let latency_ms = row.latency.map(|latency| latency.as_secs_f64() * 1000.0);
+        match self {
+            Self::Text(w) => match (&row.number, row.verdict) {
+                (Ok(n), Verdict::Prime) => writeln!(w, "{n} is prime")?,
+                (Ok(n), Verdict::NotPrime) => writeln!(w, "{n} is not prime")?,
+                // Already logged
+                _ => {}
+            },
+            Self::Csv(csv) => {
+                let number = match &row.number {
+                    Ok(n) => n.to_string(),
+                    Err(line) => line.clone(),
+                };
+                let latency_ms = latency_ms.map(|ms| format!("{ms:.3}")).unwrap_or_default();
+                csv.write_record([&number, row.verdict.as_str(), &latency_ms])?;
+            }
+            Self::Jsonl(w) => {
+                let number = match &row.number {
+                    Ok(n) => serde_json::Value::Number(n.clone()),
+                    Err(line) => serde_json::Value::String(line.clone()),
+                };
+                let line = JsonRow {
+                    number,
+                    verdict: row.verdict,
+                    latency_ms,
+                };
+                serde_json::to_writer(&mut *w, &line)?;
+                writeln!(w)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            Self::Text(w) | Self::Jsonl(w) => w.flush()?,
+            Self::Csv(csv) => csv.flush()?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+    use tokio::io::AsyncWriteExt;
+
+    /// A client talking to a server that calls 2, 3, 5 and 7 prime, and
+    /// nothing else, along with every request the server got
+    fn client() -> (Client, Arc<Mutex<Vec<String>>>) {
+        server(true)
+    }
+
+    /// `client`, for a server that may not know `certifyPrime`, and hangs up
+    /// on it as a malformed request if so
+    fn server(certifies: bool) -> (Client, Arc<Mutex<Vec<String>>>) {
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let (server_read, mut server_write) = tokio::io::split(server);
+        let requests = Arc::new(Mutex::new(vec![]));
+        let seen = requests.clone();
+        tokio::spawn(async move {
+            let mut lines = BufReader::new(server_read).lines();
+            while let Some(line) = lines.next_line().await.unwrap() {
+                let request: serde_json::Value = serde_json::from_str(&line).unwrap();
+                let n = request["number"].as_u64();
+                let prime = [2, 3, 5, 7].iter().any(|&p| n == Some(p));
+                seen.lock().unwrap().push(line);
+                let answer = match request["method"].as_str().unwrap() {
+                    "certifyPrime" if !certifies => {
+                        server_write.write_all(b"malformed request").await.unwrap();
+                        return;
+                    }
+                    "certifyPrime" if prime => format!(
+                        "{{\"method\":\"certifyPrime\",\"certificate\":{{\"small\":{}}}}}\n",
+                        n.unwrap()
+                    ),
+                    method => format!("{{\"method\":\"{method}\",\"prime\":{prime}}}\n"),
+                };
+                server_write.write_all(answer.as_bytes()).await.unwrap();
+            }
+        });
+        let (client_read, client_write) = tokio::io::split(client);
+        (Client::new(client_read, client_write), requests)
+    }
+
+    async fn batch(input: &str, format: Format) -> (String, Summary) {
+        let mut output = vec![];
+        let summary = run(
+            &client().0,
+            None,
+            input.as_bytes(),
+            &mut output,
+            format,
+            2,
+            false,
+        )
+        .await
+        .unwrap();
+        (String::from_utf8(output).unwrap(), summary)
+    }
+
+    #[tokio::test]
+    async fn csv_rows_in_input_order() {
+        let (output, summary) = batch("7\n8\n\n1e400\nabc,\"def\"\n2\n", Format::Csv).await;
+        let rows: Vec<Vec<String>> = csv::Reader::from_reader(output.as_bytes())
+            .records()
+            .map(|record| record.unwrap().iter().map(String::from).collect())
+            .collect();
+        let verdicts: Vec<_> = rows.iter().map(|r| (&*r[0], &*r[1])).collect();
+        assert_eq!(
+            verdicts,
+            [
+                ("7", "prime"),
+                ("8", "not_prime"),
+                ("1e400", "not_prime"),
+                ("abc,\"def\"", "error"),
+                ("2", "prime"),
+            ]
+        );
+        assert!(rows[0][2].parse::<f64>().is_ok());
+        assert_eq!(rows[3][2], "");
+        assert_eq!((summary.count, summary.primes, summary.errors), (5, 2, 1));
+    }
+
+    #[tokio::test]
+    async fn jsonl_keeps_numbers_exact() {
+        let big = "123456789012345678901234567890";
+        let (output, _) = batch(&format!("{big}\n5\n"), Format::Jsonl).await;
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with(&format!("{{\"number\":{big},")));
+        let row: serde_json::Value = serde_json::from_str(lines[1]).unwrap();
+        assert_eq!(row["verdict"], "prime");
+        assert!(row["latency_ms"].is_number());
+    }
+
+    #[tokio::test]
+    async fn text_skips_errors() {
+        let (output, summary) = batch("3\nx\n4\n", Format::Text).await;
+        assert_eq!(output, "3 is prime\n4 is not prime\n");
+        assert_eq!(summary.errors, 1);
+    }
+
+    #[tokio::test]
+    async fn verifying_catches_wrong_answers() {
+        let mut output = vec![];
+        let summary = run(
+            &client().0,
+            None,
+            "7\n11\n12\n".as_bytes(),
+            &mut output,
+            Format::Csv,
+            2,
+            true,
+        )
+        .await
+        .unwrap();
+        assert_eq!(summary.disagreements, Some(1));
+        assert_eq!(summary.primes, 1);
+    }
+
+    #[tokio::test]
+    async fn certificates_only_for_primes() {
+        let (client, asked) = client();
+        let (certifier, certified) = server(true);
+        let summary = run(
+            &client,
+            Some(&certifier),
+            "7\n8\n7.5\n3\n".as_bytes(),
+            &mut vec![],
+            Format::Csv,
+            2,
+            false,
+        )
+        .await
+        .unwrap();
+        assert_eq!(summary.disagreements, Some(0));
+        assert!(
+            asked
+                .lock()
+                .unwrap()
+                .iter()
+                .all(|line| line.contains("isPrime"))
+        );
+        assert_eq!(
+            *certified.lock().unwrap(),
+            [
+                r#"{"method":"certifyPrime","number":7}"#,
+                r#"{"method":"certifyPrime","number":3}"#
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn no_certificates_from_a_server_without_them() {
+        let (client, _) = client();
+        let (certifier, certified) = server(false);
+        let mut output = vec![];
+        let summary = run(
+            &client,
+            Some(&certifier),
+            "7\n8\n5\n3\n2\n".as_bytes(),
+            &mut output,
+            Format::Text,
+            2,
+            true,
+        )
+        .await
+        .unwrap();
+        // Every answer still comes through and gets checked locally, and the
+        // server only ever hears the one certifyPrime
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "7 is prime\n8 is not prime\n5 is prime\n3 is prime\n2 is prime\n"
+        );
+        assert_eq!((summary.primes, summary.errors), (4, 0));
+        assert_eq!(summary.disagreements, Some(0));
+        assert_eq!(certified.lock().unwrap().len(), 1);
+    }
+}
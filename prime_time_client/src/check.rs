@@ -0,0 +1,366 @@
+use crate::Response;
+use anyhow::Result;
+use std::fmt;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{Instant, sleep, timeout_at};
+
+// 2^127 - 1
+const MERSENNE_127: &str = "170141183460469231731687303715884105727";
+// 2^127 + 1, a multiple of 3
+const PAST_MERSENNE_127: &str = "170141183460469231731687303715884105729";
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// How long to wait for each case's answers, in seconds
+    #[arg(long, default_value_t = 5)]
+    pub timeout_secs: u64,
+}
+
+/// What a case should get back, in order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Expected {
+    /// An `isPrime` answer with this verdict
+    Answer(bool),
+    /// Anything that isn't an `isPrime` answer, and then the server hanging up
+    Malformed,
+}
+
+impl fmt::Display for Expected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Answer(prime) => write!(f, r#"{{"method":"isPrime","prime":{prime}}}\n"#),
+            Self::Malformed => write!(f, "<malformed response>"),
+        }
+    }
+}
+
+/// A scripted exchange over a connection of its own
+#[derive(Debug, Clone)]
+pub struct Case {
+    name: String,
+    /// Each sent with a write of its own
+    writes: Vec<Vec<u8>>,
+    expected: Vec<Expected>,
+}
+
+impl Case {
+    /// Sends `request` in one write
+    fn new(name: impl Into<String>, request: impl Into<Vec<u8>>, expected: Vec<Expected>) -> Self {
+        Self {
+            name: name.into(),
+            writes: vec![request.into()],
+            expected,
+        }
+    }
+
+    /// Sends the same bytes one at a time
+    fn bytewise(mut self) -> Self {
+        self.writes = self.writes.concat().into_iter().map(|b| vec![b]).collect();
+        self
+    }
+
+    fn hangs_up(&self) -> bool {
+        self.expected.last() == Some(&Expected::Malformed)
+    }
+}
+
+/// An `isPrime` request for `number`, newline included
+fn request(number: &str) -> String {
+    format!("{{\"method\":\"isPrime\",\"number\":{number}}}\n")
+}
+
+/// The cases the official checker covers, give or take
+pub fn suite() -> Vec<Case> {
+    use Expected::{Answer, Malformed};
+    let mut cases = vec![
+        Case::new("prime", request("7"), vec![Answer(true)]),
+        Case::new("composite", request("8"), vec![Answer(false)]),
+        Case::new(
+            "zero, one and negatives",
+            [request("0"), request("1"), request("-7")].concat(),
+            vec![Answer(false); 3],
+        ),
+        Case::new(
+            "extra field",
+            "{\"method\":\"isPrime\",\"number\":13,\"extra\":\"field\"}\n",
+            vec![Answer(true)],
+        ),
+        Case::new(
+            "extra fields first",
+            "{\"extra\":{\"nested\":[1,2]},\"number\":4,\"method\":\"isPrime\"}\n",
+            vec![Answer(false)],
+        ),
+        Case::new(
+            "extra jsonrpc field",
+            "{\"method\":\"isPrime\",\"number\":7,\"jsonrpc\":\"2.0\"}\n",
+            vec![Answer(true)],
+        ),
+        Case::new(
+            "floats",
+            [request("7.5"), request("-2.5"), request("12.25")].concat(),
+            vec![Answer(false); 3],
+        ),
+        Case::new(
+            "huge integers",
+            [
+                request(MERSENNE_127),
+                request(PAST_MERSENNE_127),
+                request(&format!("1{}", "0".repeat(400))),
+            ]
+            .concat(),
+            vec![Answer(true), Answer(false), Answer(false)],
+        ),
+        Case::new(
+            "pipelined in one write",
+            (0..100)
+                .map(|n| request(&n.to_string()))
+                .collect::<String>(),
+            (0..100u32)
+                .map(|n| Answer(n >= 2 && (2..n).all(|d| !n.is_multiple_of(d))))
+                .collect(),
+        ),
+        Case::new(
+            "split byte by byte",
+            [request("97"), request("98")].concat(),
+            vec![Answer(true), Answer(false)],
+        )
+        .bytewise(),
+    ];
+    let malformed = [
+        ("not JSON", "hello"),
+        ("empty line", ""),
+        ("unterminated object", r#"{"method":"isPrime","number":7"#),
+        ("not an object", "7"),
+        ("a string", r#""isPrime""#),
+        ("empty array", "[]"),
+        ("array", "[1,2]"),
+        ("missing method", r#"{"number":7}"#),
+        ("missing number", r#"{"method":"isPrime"}"#),
+        ("wrong method", r#"{"method":"isComposite","number":7}"#),
+        ("method not a string", r#"{"method":1,"number":7}"#),
+        ("number as a string", r#"{"method":"isPrime","number":"7"}"#),
+        ("number as a bool", r#"{"method":"isPrime","number":true}"#),
+        ("number is null", r#"{"method":"isPrime","number":null}"#),
+    ];
+    for (name, line) in malformed {
+        // After a request that should still be answered
+        cases.push(Case::new(
+            format!("malformed: {name}"),
+            request("3") + line + "\n",
+            vec![Answer(true), Malformed],
+        ));
+    }
+    cases
+}
+
+/// What came back over a case's connection
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Transcript {
+    /// Split after each newline, with whatever followed the last one at the
+    /// end
+    lines: Vec<Vec<u8>>,
+    /// Whether the server hung up before the time ran out
+    closed: bool,
+}
+
+impl Transcript {
+    fn new(bytes: &[u8], closed: bool) -> Self {
+        let lines = bytes
+            .split_inclusive(|&b| b == b'\n')
+            .map(<[u8]>::to_vec)
+            .collect();
+        Self { lines, closed }
+    }
+
+    fn answered(&self) -> usize {
+        self.lines.iter().filter(|l| l.ends_with(b"\n")).count()
+    }
+}
+
+/// Whether `actual` is what `expected` asks for
+fn matches(expected: Expected, actual: &[u8]) -> bool {
+    let answer = std::str::from_utf8(actual)
+        .ok()
+        .and_then(|line| serde_json::from_str::<Response>(line).ok())
+        .filter(|response| response.method == "isPrime")
+        .map(|response| response.prime);
+    match expected {
+        Expected::Answer(prime) => actual.ends_with(b"\n") && answer == Some(prime),
+        Expected::Malformed => !actual.is_empty() && answer.is_none(),
+    }
+}
+
+/// How a case went
+#[derive(Debug, Clone)]
+pub struct Outcome {
+    pub name: String,
+    pub passed: bool,
+    /// Expected against actual, line by line
+    pub diff: Vec<String>,
+}
+
+fn judge(case: &Case, transcript: &Transcript) -> Outcome {
+    let mut diff = vec![];
+    let mismatch = |diff: &mut Vec<String>, expected: String, actual: String| {
+        diff.push(format!("- {expected}"));
+        diff.push(format!("+ {actual}"));
+    };
+    let mut passed = true;
+    for i in 0..case.expected.len().max(transcript.lines.len()) {
+        let expected = case.expected.get(i);
+        let actual = transcript.lines.get(i);
+        match (expected, actual) {
+            (Some(&e), Some(a)) if matches(e, a) => diff.push(format!("  {}", a.escape_ascii())),
+            _ => {
+                passed = false;
+                mismatch(
+                    &mut diff,
+                    expected.map_or("<nothing>".into(), Expected::to_string),
+                    actual.map_or("<nothing>".into(), |a| a.escape_ascii().to_string()),
+                );
+            }
+        }
+    }
+    if case.hangs_up() {
+        if transcript.closed {
+            diff.push("  <connection closed>".into());
+        } else {
+            passed = false;
+            mismatch(
+                &mut diff,
+                "<connection closed>".into(),
+                "<connection open>".into(),
+            );
+        }
+    }
+    Outcome {
+        name: case.name.clone(),
+        passed,
+        diff,
+    }
+}
+
+/// Plays `case` against the server at `address`, giving up on any answers
+/// still missing after `wait`
+async fn exchange(address: &str, case: &Case, wait: Duration) -> Result<Transcript> {
+    let deadline = Instant::now() + wait;
+    let mut stream = TcpStream::connect(address).await?;
+    stream.set_nodelay(true)?;
+    let split = case.writes.len() > 1;
+    for write in &case.writes {
+        stream.write_all(write).await?;
+        if split {
+            // Give each write a packet of its own
+            sleep(Duration::from_millis(1)).await;
+        }
+    }
+    let mut received = vec![];
+    let mut buf = [0; 4096];
+    let mut closed = false;
+    loop {
+        let transcript = Transcript::new(&received, false);
+        if !case.hangs_up() && transcript.answered() >= case.expected.len() {
+            break;
+        }
+        match timeout_at(deadline, stream.read(&mut buf)).await {
+            Ok(Ok(0)) | Ok(Err(_)) => {
+                closed = true;
+                break;
+            }
+            Ok(Ok(n)) => received.extend_from_slice(&buf[..n]),
+            Err(_) => break,
+        }
+    }
+    Ok(Transcript::new(&received, closed))
+}
+
+/// Runs every case in the suite against the server at `address`
+pub async fn run(address: &str, args: &Args) -> Result<Vec<Outcome>> {
+    let wait = Duration::from_secs(args.timeout_secs);
+    let mut outcomes = vec![];
+    for case in suite() {
+        let outcome = match exchange(address, &case, wait).await {
+            Ok(transcript) => judge(&case, &transcript),
+            Err(e) => Outcome {
+                name: case.name.clone(),
+                passed: false,
+                diff: vec![format!("! {e}")],
+            },
+        };
+        if outcome.passed {
+            println!("PASS {}", outcome.name);
+        } else {
+            println!("FAIL {}", outcome.name);
+            for line in &outcome.diff {
+                println!("    {line}");
+            }
+        }
+        outcomes.push(outcome);
+    }
+    Ok(outcomes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn case(expected: Vec<Expected>) -> Case {
+        Case::new("test", "", expected)
+    }
+
+    #[test]
+    fn answers_are_read_as_json() {
+        let case = case(vec![Expected::Answer(true), Expected::Answer(false)]);
+        let transcript = Transcript::new(
+            b"{\"prime\": true, \"method\": \"isPrime\"}\n{\"method\":\"isPrime\",\"prime\":false}\n",
+            false,
+        );
+        assert!(judge(&case, &transcript).passed);
+        // Unterminated
+        let transcript = Transcript::new(b"{\"method\":\"isPrime\",\"prime\":true}", true);
+        assert!(!judge(&case, &transcript).passed);
+    }
+
+    #[test]
+    fn wrong_answers_show_a_diff() {
+        let case = case(vec![Expected::Answer(true)]);
+        let transcript = Transcript::new(b"{\"method\":\"isPrime\",\"prime\":false}\n", false);
+        let outcome = judge(&case, &transcript);
+        assert!(!outcome.passed);
+        assert_eq!(
+            outcome.diff,
+            [
+                r#"- {"method":"isPrime","prime":true}\n"#,
+                r#"+ {\"method\":\"isPrime\",\"prime\":false}\n"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_requests_need_a_reply_and_a_hang_up() {
+        let case = case(vec![Expected::Answer(true), Expected::Malformed]);
+        let answer = b"{\"method\":\"isPrime\",\"prime\":true}\n".to_vec();
+        let replied = [answer.clone(), b"malformed request".to_vec()].concat();
+        assert!(judge(&case, &Transcript::new(&replied, true)).passed);
+        let outcome = judge(&case, &Transcript::new(&replied, false));
+        assert_eq!(outcome.diff.last().unwrap(), "+ <connection open>");
+        // Silence isn't a reply
+        assert!(!judge(&case, &Transcript::new(&answer, true)).passed);
+        // Nor is accepting it
+        let accepted = [answer.clone(), answer].concat();
+        assert!(!judge(&case, &Transcript::new(&accepted, true)).passed);
+    }
+
+    #[test]
+    fn suite_expects_one_answer_per_request() {
+        for case in suite() {
+            let sent = case.writes.concat();
+            let lines = sent.iter().filter(|&&b| b == b'\n').count();
+            assert_eq!(lines, case.expected.len(), "{}", case.name);
+        }
+        let bytewise = suite().into_iter().find(|c| c.name == "split byte by byte");
+        assert!(bytewise.unwrap().writes.iter().all(|w| w.len() == 1));
+    }
+}
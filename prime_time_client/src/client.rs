@@ -0,0 +1,235 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex as SyncMutex};
+use std::time::Instant;
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::{Mutex, oneshot};
+use tracing::{debug, error};
+
+type Waiting = Arc<SyncMutex<Queue>>;
+
+/// Where the answer to a request turns up
+pub type Answer = oneshot::Receiver<Result<Reply, Error>>;
+
+/// The requests waiting for an answer, and why no more will come once the
+/// connection is done
+#[derive(Default)]
+struct Queue {
+    waiting: VecDeque<oneshot::Sender<Result<Reply, Error>>>,
+    closed: Option<Error>,
+}
+
+/// A line the server sent back, and when it was read off the connection,
+/// which may be well before anyone gets round to looking at it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    pub line: String,
+    pub received: Instant,
+}
+
+#[derive(Debug, Error, Clone)]
+pub enum Error {
+    #[error("I/O error: {0}")]
+    Io(Arc<std::io::Error>),
+    #[error("Server closed the connection before answering")]
+    Closed,
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(Arc::new(e))
+    }
+}
+
+/// A connection to a prime_time server with any number of requests in flight.
+///
+/// The server answers in the order it was asked, so each line it sends back
+/// belongs to the oldest request still waiting.
+pub struct Client {
+    writer: Mutex<Box<dyn AsyncWrite + Send + Unpin>>,
+    waiting: Waiting,
+}
+
+impl Client {
+    pub fn new<R, W>(reader: R, writer: W) -> Self
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        let waiting = Waiting::default();
+        tokio::spawn(read_responses(reader, waiting.clone()));
+        Self {
+            writer: Mutex::new(Box::new(writer)),
+            waiting,
+        }
+    }
+
+    /// Connects to the server at `address`
+    pub async fn connect(address: &str) -> std::io::Result<Self> {
+        let (reader, writer) = TcpStream::connect(address).await?.into_split();
+        Ok(Self::new(reader, writer))
+    }
+
+    /// Sends `request` as one line, and returns a receiver for the line that
+    /// answers it
+    pub async fn send<T: Serialize>(&self, request: &T) -> Result<Answer, Error> {
+        let mut line = serde_json::to_vec(request).expect("Requests always serialize");
+        line.push(b'\n');
+        self.send_line(&line).await
+    }
+
+    /// Sends raw bytes that make up exactly one request line, newline included.
+    /// Fails straight away once the server has stopped answering.
+    pub async fn send_line(&self, line: &[u8]) -> Result<Answer, Error> {
+        let (tx, rx) = oneshot::channel();
+        // Queue the answer under the writer lock, so that the queue is in the
+        // order the requests hit the wire
+        let mut writer = self.writer.lock().await;
+        {
+            let mut queue = self.waiting.lock().unwrap();
+            if let Some(e) = &queue.closed {
+                return Err(e.clone());
+            }
+            queue.waiting.push_back(tx);
+        }
+        if let Err(e) = writer.write_all(line).await {
+            self.waiting.lock().unwrap().waiting.pop_back();
+            return Err(e.into());
+        }
+        debug!("Sent {}", String::from_utf8_lossy(line).trim_end());
+        Ok(rx)
+    }
+
+    /// Tells the server there are no more requests coming
+    pub async fn finish(&self) -> Result<(), Error> {
+        Ok(self.writer.lock().await.shutdown().await?)
+    }
+}
+
+async fn read_responses<R: AsyncRead + Unpin>(reader: R, waiting: Waiting) {
+    let mut lines = BufReader::new(reader).lines();
+    let reason = loop {
+        match lines.next_line().await {
+            Ok(Some(line)) => match waiting.lock().unwrap().waiting.pop_front() {
+                Some(tx) => {
+                    let received = Instant::now();
+                    let _ = tx.send(Ok(Reply { line, received }));
+                }
+                None => error!("Unexpected response: {line}"),
+            },
+            Ok(None) => break Error::Closed,
+            Err(e) => break e.into(),
+        }
+    };
+    // Under the same lock as the drain, so nothing can be queued after it
+    let mut queue = waiting.lock().unwrap();
+    for tx in queue.waiting.drain(..) {
+        let _ = tx.send(Err(reason.clone()));
+    }
+    queue.closed = Some(reason);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+
+    /// A client talking to a server that answers each line with its number
+    /// and the line itself, and hangs up after `answers` of them
+    fn client(answers: usize) -> Client {
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let (server_read, mut server_write) = tokio::io::split(server);
+        tokio::spawn(async move {
+            let mut lines = BufReader::new(server_read).lines();
+            for i in 0..answers {
+                let Some(line) = lines.next_line().await.unwrap() else {
+                    break;
+                };
+                tokio::task::yield_now().await;
+                let answer = format!("{i} {line}\n");
+                server_write.write_all(answer.as_bytes()).await.unwrap();
+            }
+        });
+        let (client_read, client_write) = tokio::io::split(client);
+        Client::new(client_read, client_write)
+    }
+
+    #[tokio::test]
+    async fn answers_match_requests() {
+        let client = client(usize::MAX);
+        let mut pending = vec![];
+        for i in 0..100 {
+            pending.push(client.send(&i).await.unwrap());
+        }
+        for (i, rx) in pending.into_iter().enumerate() {
+            assert_eq!(rx.await.unwrap().unwrap().line, format!("{i} {i}"));
+        }
+        let last = client.send(&"last").await.unwrap();
+        assert_eq!(last.await.unwrap().unwrap().line, "100 \"last\"");
+    }
+
+    #[tokio::test]
+    async fn replies_are_timed_as_they_arrive() {
+        let client = client(usize::MAX);
+        let rx = client.send(&1).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let reply = rx.await.unwrap().unwrap();
+        assert!(reply.received.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn hanging_up_fails_whatever_is_waiting() {
+        let client = client(2);
+        let mut pending = vec![];
+        for i in 0..4 {
+            pending.push(client.send(&i).await.unwrap());
+        }
+        let mut answered = vec![];
+        for rx in pending {
+            answered.push(rx.await.unwrap().is_ok());
+        }
+        assert_eq!(answered, [true, true, false, false]);
+    }
+
+    #[tokio::test]
+    async fn requests_after_a_hang_up_fail() {
+        // Still reading, as a socket's peer might, but never answering
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let (mut server_read, mut server_write) = tokio::io::split(server);
+        server_write.shutdown().await.unwrap();
+        tokio::spawn(async move {
+            let mut sink = vec![];
+            server_read.read_to_end(&mut sink).await
+        });
+        let (client_read, client_write) = tokio::io::split(client);
+        let client = Client::new(client_read, client_write);
+        let first = client.send(&1).await.unwrap();
+        assert!(matches!(first.await.unwrap(), Err(Error::Closed)));
+        let after = tokio::time::timeout(Duration::from_secs(1), async {
+            match client.send(&2).await {
+                Ok(rx) => rx.await.unwrap(),
+                Err(e) => Err(e),
+            }
+        });
+        assert!(matches!(after.await, Ok(Err(Error::Closed))));
+    }
+
+    #[tokio::test]
+    async fn requests_are_newline_terminated() {
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let (client_read, client_write) = tokio::io::split(client);
+        let client = Client::new(client_read, client_write);
+        let _answer = client
+            .send(&serde_json::json!({"number": 7}))
+            .await
+            .unwrap();
+        client.finish().await.unwrap();
+        let mut sent = String::new();
+        let (mut server_read, _server_write) = tokio::io::split(server);
+        server_read.read_to_string(&mut sent).await.unwrap();
+        assert_eq!(sent, "{\"number\":7}\n");
+    }
+}
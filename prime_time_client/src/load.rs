@@ -0,0 +1,530 @@
+use crate::Response;
+use crate::client::{self, Client, Reply};
+use anyhow::{Result, bail};
+use hdrhistogram::Histogram;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Semaphore, mpsc};
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, MissedTickBehavior, interval, sleep, sleep_until};
+use tracing::{debug, warn};
+
+/// Requests that no server should accept
+const MALFORMED: [&str; 6] = [
+    r#"{"method":"isPrime","number":"7"}"#,
+    r#"{"method":"isPrime"}"#,
+    r#"{"method":"isComposite","number":7}"#,
+    r#"{"number":7}"#,
+    r#"{"method":"isPrime","number":7"#,
+    "isPrime 7",
+];
+
+// Slowest round trip the histograms tell apart, in microseconds
+const SLOWEST: u64 = 60_000_000;
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// Connections to spread the requests over
+    #[arg(long, default_value_t = 8)]
+    pub connections: usize,
+
+    /// Requests per second across all connections, or 0 to send as fast as
+    /// answers come back
+    #[arg(long, default_value_t = 1000)]
+    pub rate: u32,
+
+    /// How long to keep sending, in seconds
+    #[arg(long, default_value_t = 10)]
+    pub duration_secs: u64,
+
+    /// Weight of numbers below 2^32 in the mix
+    #[arg(long, default_value_t = 80)]
+    pub small: u32,
+
+    /// Weight of numbers with `--large-digits` digits in the mix
+    #[arg(long, default_value_t = 15)]
+    pub large: u32,
+
+    /// Weight of malformed requests in the mix
+    #[arg(long, default_value_t = 5)]
+    pub malformed: u32,
+
+    /// Decimal digits in each large number
+    #[arg(long, default_value_t = 100)]
+    pub large_digits: usize,
+
+    /// Seed for picking requests, to repeat a run exactly
+    #[arg(long)]
+    pub seed: Option<u64>,
+}
+
+/// What a load run sends
+#[derive(Debug, Clone)]
+pub struct Load {
+    pub connections: usize,
+    /// Time between requests on each connection, if they're paced at all
+    pub period: Option<Duration>,
+    pub duration: Duration,
+    pub mix: Mix,
+    pub large_digits: usize,
+    pub seed: u64,
+    pub max_in_flight: usize,
+}
+
+impl Load {
+    pub fn new(args: &Args, max_in_flight: usize) -> Result<Self> {
+        if args.connections == 0 {
+            bail!("Need at least one connection");
+        }
+        if args.large_digits == 0 {
+            bail!("Large numbers need at least one digit");
+        }
+        let period = (args.rate > 0)
+            .then(|| Duration::from_secs_f64(args.connections as f64 / args.rate as f64));
+        Ok(Self {
+            connections: args.connections,
+            period,
+            duration: Duration::from_secs(args.duration_secs),
+            mix: Mix::new(args.small, args.large, args.malformed)?,
+            large_digits: args.large_digits,
+            seed: args.seed.unwrap_or_else(|| fastrand::u64(..)),
+            max_in_flight: max_in_flight.max(1),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Kind {
+    Small,
+    Large,
+    Malformed,
+}
+
+impl Kind {
+    const ALL: [Kind; 3] = [Kind::Small, Kind::Large, Kind::Malformed];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Small => "small",
+            Self::Large => "large",
+            Self::Malformed => "malformed",
+        }
+    }
+}
+
+/// Relative weights of each kind of request
+#[derive(Debug, Clone, Copy)]
+pub struct Mix {
+    small: u32,
+    large: u32,
+    malformed: u32,
+}
+
+impl Mix {
+    pub fn new(small: u32, large: u32, malformed: u32) -> Result<Self> {
+        let Some(total) = small
+            .checked_add(large)
+            .and_then(|w| w.checked_add(malformed))
+        else {
+            bail!("The request mix weights are too large");
+        };
+        if total == 0 {
+            bail!("The request mix needs a weight above 0");
+        }
+        Ok(Self {
+            small,
+            large,
+            malformed,
+        })
+    }
+
+    fn pick(&self, rng: &mut fastrand::Rng) -> Kind {
+        let roll = rng.u32(..self.small + self.large + self.malformed);
+        if roll < self.small {
+            Kind::Small
+        } else if roll < self.small + self.large {
+            Kind::Large
+        } else {
+            Kind::Malformed
+        }
+    }
+}
+
+/// A request line of the given kind, newline included
+fn request(kind: Kind, rng: &mut fastrand::Rng, large_digits: usize) -> String {
+    let number = match kind {
+        Kind::Small => rng.u32(..).to_string(),
+        Kind::Large => {
+            // Odd and not a multiple of 5, so it takes real work to answer
+            let mut digits = String::with_capacity(large_digits);
+            if large_digits > 1 {
+                digits.push(rng.char('1'..='9'));
+                for _ in 2..large_digits {
+                    digits.push(rng.digit(10));
+                }
+            }
+            digits.push(['1', '3', '7', '9'][rng.usize(..4)]);
+            digits
+        }
+        Kind::Malformed => return format!("{}\n", MALFORMED[rng.usize(..MALFORMED.len())]),
+    };
+    format!("{{\"method\":\"isPrime\",\"number\":{number}}}\n")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Failure {
+    /// Couldn't connect, or the connection broke while sending
+    Connection,
+    /// The connection closed before the answer came
+    NoAnswer,
+    /// A well-formed request got something other than an `isPrime` answer
+    BadResponse,
+    /// A malformed request got an `isPrime` answer
+    AcceptedMalformed,
+}
+
+impl Failure {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Connection => "connection",
+            Self::NoAnswer => "no answer",
+            Self::BadResponse => "bad response",
+            Self::AcceptedMalformed => "accepted malformed",
+        }
+    }
+}
+
+/// How one kind of request fared
+#[derive(Debug, Clone)]
+pub struct Stats {
+    /// Round trips of the requests answered correctly, in microseconds
+    pub latency: Histogram<u64>,
+    pub failures: BTreeMap<Failure, u64>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            latency: Histogram::new_with_bounds(1, SLOWEST, 3).expect("The bounds are valid"),
+            failures: BTreeMap::new(),
+        }
+    }
+}
+
+impl Stats {
+    pub fn requests(&self) -> u64 {
+        self.latency.len() + self.errors()
+    }
+
+    pub fn errors(&self) -> u64 {
+        self.failures.values().sum()
+    }
+
+    fn add(&mut self, other: &Stats) {
+        self.latency
+            .add(&other.latency)
+            .expect("Histograms resize to fit");
+        for (&failure, count) in &other.failures {
+            *self.failures.entry(failure).or_default() += count;
+        }
+    }
+}
+
+/// Everything a load run saw
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub kinds: BTreeMap<Kind, Stats>,
+    pub elapsed: Duration,
+    /// What was asked for, in requests per second
+    pub target: Option<f64>,
+}
+
+impl Report {
+    fn record(&mut self, kind: Kind, outcome: Result<Duration, Failure>) {
+        let stats = self.kinds.entry(kind).or_default();
+        match outcome {
+            Ok(latency) => stats.latency.saturating_record(latency.as_micros() as u64),
+            Err(failure) => *stats.failures.entry(failure).or_default() += 1,
+        }
+    }
+
+    fn add(&mut self, other: &Report) {
+        for (&kind, stats) in &other.kinds {
+            self.kinds.entry(kind).or_default().add(stats);
+        }
+    }
+
+    pub fn total(&self) -> Stats {
+        let mut total = Stats::default();
+        for stats in self.kinds.values() {
+            total.add(stats);
+        }
+        total
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ms = |micros: u64| format!("{:.3}ms", micros as f64 / 1000.0);
+        writeln!(
+            f,
+            "{:<10} {:>9} {:>7} {:>10} {:>10} {:>10} {:>10}",
+            "kind", "requests", "errors", "p50", "p90", "p99", "max"
+        )?;
+        let total = self.total();
+        let kinds = Kind::ALL
+            .iter()
+            .filter_map(|kind| Some((kind.name(), self.kinds.get(kind)?)));
+        for (name, stats) in kinds.chain([("all", &total)]) {
+            let latency = &stats.latency;
+            writeln!(
+                f,
+                "{:<10} {:>9} {:>7} {:>10} {:>10} {:>10} {:>10}",
+                name,
+                stats.requests(),
+                stats.errors(),
+                ms(latency.value_at_quantile(0.5)),
+                ms(latency.value_at_quantile(0.9)),
+                ms(latency.value_at_quantile(0.99)),
+                ms(latency.max()),
+            )?;
+        }
+        for (failure, count) in &total.failures {
+            writeln!(f, "{}: {count}", failure.name())?;
+        }
+        let rate = total.requests() as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON);
+        write!(f, "{:.0} requests/s over {:.3?}", rate, self.elapsed)?;
+        if let Some(target) = self.target {
+            write!(f, " (target {target:.0})")?;
+        }
+        Ok(())
+    }
+}
+
+/// Sends `load` to the server at `address` and reports how it went
+pub async fn run(address: &str, load: &Load) -> Result<Report> {
+    let start = Instant::now();
+    let deadline = start + load.duration;
+    let mut workers = vec![];
+    for i in 0..load.connections {
+        let address = address.to_string();
+        let load = load.clone();
+        let seed = load.seed.wrapping_add(i as u64);
+        workers.push(tokio::spawn(async move {
+            worker(&address, &load, seed, deadline).await
+        }));
+    }
+    let mut report = Report {
+        target: load
+            .period
+            .map(|period| load.connections as f64 / period.as_secs_f64()),
+        ..Report::default()
+    };
+    for worker in workers {
+        report.add(&worker.await?);
+    }
+    report.elapsed = start.elapsed();
+    Ok(report)
+}
+
+/// A request on its way, or one that never made it
+enum Pending {
+    Sent(Kind, JoinHandle<Result<Duration, Failure>>),
+    Failed(Kind, Failure),
+}
+
+/// Keeps one connection busy until `deadline`, opening a new one whenever the
+/// server hangs up
+async fn worker(address: &str, load: &Load, seed: u64, deadline: Instant) -> Report {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let ((), report) = tokio::join!(send(address, load, seed, deadline, tx), collect(rx));
+    report
+}
+
+async fn send(
+    address: &str,
+    load: &Load,
+    seed: u64,
+    deadline: Instant,
+    pending: mpsc::UnboundedSender<Pending>,
+) {
+    let mut rng = fastrand::Rng::with_seed(seed);
+    let window = Arc::new(Semaphore::new(load.max_in_flight));
+    let mut ticks = load.period.map(|period| {
+        let mut ticks = interval(period);
+        // Fall behind rather than burst to catch up
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        ticks
+    });
+    let mut client = None;
+    loop {
+        let ready = async {
+            if let Some(ticks) = &mut ticks {
+                ticks.tick().await;
+            }
+            window.clone().acquire_owned().await
+        };
+        let permit = tokio::select! {
+            _ = sleep_until(deadline) => break,
+            permit = ready => permit.expect("The window is never closed"),
+        };
+        let kind = load.mix.pick(&mut rng);
+        let line = request(kind, &mut rng, load.large_digits);
+        let connection = match &client {
+            Some(connection) => connection,
+            None => match Client::connect(address).await {
+                Ok(connection) => client.insert(connection),
+                Err(e) => {
+                    warn!("Couldn't connect: {e}");
+                    let _ = pending.send(Pending::Failed(kind, Failure::Connection));
+                    sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            },
+        };
+        let sent = Instant::now();
+        let answer = match connection.send_line(line.as_bytes()).await {
+            Ok(answer) => answer,
+            Err(e) => {
+                debug!("Lost the connection: {e}");
+                client = None;
+                let _ = pending.send(Pending::Failed(kind, Failure::Connection));
+                continue;
+            }
+        };
+        let timed = tokio::spawn(async move {
+            let outcome = judge(kind, answer).await;
+            drop(permit);
+            outcome.map(|received| received - sent)
+        });
+        let _ = pending.send(Pending::Sent(kind, timed));
+        if kind == Kind::Malformed {
+            // The server hangs up after a malformed request, so carry on over
+            // a fresh connection
+            client = None;
+        }
+    }
+}
+
+/// When the answer arrived, if it was the right one for `kind`
+async fn judge(kind: Kind, answer: client::Answer) -> Result<Instant, Failure> {
+    let Ok(Ok(Reply { line, received })) = answer.await else {
+        return Err(Failure::NoAnswer);
+    };
+    let received = Instant::from_std(received);
+    let answered =
+        serde_json::from_str::<Response>(&line).is_ok_and(|response| response.method == "isPrime");
+    match (kind, answered) {
+        (Kind::Malformed, true) => Err(Failure::AcceptedMalformed),
+        (Kind::Malformed, false) => Ok(received),
+        (_, true) => Ok(received),
+        (_, false) => {
+            debug!("Bad response: {line}");
+            Err(Failure::BadResponse)
+        }
+    }
+}
+
+async fn collect(mut pending: mpsc::UnboundedReceiver<Pending>) -> Report {
+    let mut report = Report::default();
+    while let Some(pending) = pending.recv().await {
+        match pending {
+            Pending::Sent(kind, timed) => {
+                let outcome = timed.await.unwrap_or(Err(Failure::NoAnswer));
+                report.record(kind, outcome);
+            }
+            Pending::Failed(kind, failure) => report.record(kind, Err(failure)),
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    fn is_request(line: &str) -> bool {
+        serde_json::from_str::<serde_json::Value>(line)
+            .is_ok_and(|r| r["method"] == "isPrime" && r["number"].is_number())
+    }
+
+    /// A server that calls everything prime, and hangs up on anything that
+    /// isn't an `isPrime` request for a number
+    async fn server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (reader, mut writer) = stream.into_split();
+                    let mut lines = BufReader::new(reader).lines();
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        if !is_request(&line) {
+                            let _ = writer.write_all(b"malformed request").await;
+                            break;
+                        }
+                        let answer = b"{\"method\":\"isPrime\",\"prime\":true}\n";
+                        if writer.write_all(answer).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn mix_respects_weights() {
+        let mut rng = fastrand::Rng::with_seed(1);
+        let mix = Mix::new(0, 3, 1).unwrap();
+        let picks: Vec<_> = (0..4000).map(|_| mix.pick(&mut rng)).collect();
+        assert!(!picks.contains(&Kind::Small));
+        let malformed = picks.iter().filter(|&&k| k == Kind::Malformed).count();
+        assert!((800..1200).contains(&malformed), "{malformed}");
+        assert!(Mix::new(0, 0, 0).is_err());
+    }
+
+    #[test]
+    fn requests_look_the_part() {
+        let mut rng = fastrand::Rng::with_seed(2);
+        for _ in 0..100 {
+            for digits in [1, 2, 100] {
+                let line = request(Kind::Large, &mut rng, digits);
+                let parsed: serde_json::Value = serde_json::from_str(&line).unwrap();
+                let number = parsed["number"].to_string();
+                assert_eq!(number.len(), digits, "{number}");
+                assert!(!number.starts_with('0'));
+            }
+            let line = request(Kind::Small, &mut rng, 0);
+            assert!(is_request(&line));
+            let parsed: serde_json::Value = serde_json::from_str(&line).unwrap();
+            assert!(parsed["number"].as_u64().unwrap() <= u32::MAX as u64);
+            assert!(!is_request(&request(Kind::Malformed, &mut rng, 0)));
+        }
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_malformed_requests() {
+        let address = server().await;
+        let load = Load {
+            connections: 4,
+            period: Some(Duration::from_millis(2)),
+            duration: Duration::from_millis(300),
+            mix: Mix::new(5, 2, 1).unwrap(),
+            large_digits: 30,
+            seed: 3,
+            max_in_flight: 8,
+        };
+        let report = run(&address, &load).await.unwrap();
+        let total = report.total();
+        assert!(total.requests() > 100, "{report}");
+        assert_eq!(total.errors(), 0, "{report}");
+        assert!(report.kinds[&Kind::Malformed].requests() > 0);
+    }
+}
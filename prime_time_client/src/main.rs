@@ -1,91 +1,162 @@
-use anyhow::Result;
+mod batch;
+mod check;
+mod client;
+mod load;
+mod verify;
+use anyhow::{Result, bail};
+use batch::Format;
+use clap::{Parser, Subcommand};
+use client::Client;
 use serde::{Deserialize, Serialize};
-use tokio::io::{stdin, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
-use tokio::net::TcpStream;
+use std::fs::File;
+use std::io::{BufWriter, Write, stdout};
+use tokio::io::{AsyncRead, stdin};
 use tokio::select;
 use tokio::signal;
-use tracing::{debug, error, info};
-use tracing_subscriber::{fmt, prelude::*, EnvFilter};
+use tracing_subscriber::{EnvFilter, fmt, prelude::*};
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    tracing_subscriber::registry()
-        .with(fmt::layer())
-        .with(EnvFilter::from_default_env())
-        .init();
+#[derive(Debug, Parser)]
+#[command(about = "Asks a prime time server about the numbers on stdin, one per line")]
+struct Args {
+    /// Server to connect to
+    #[arg(long, default_value = "localhost:1337")]
+    address: String,
 
-    let stdin = stdin();
-    let mut reader = BufReader::new(stdin);
+    /// Requests sent ahead of the answers coming back
+    #[arg(long, default_value_t = 64)]
+    max_in_flight: usize,
 
-    let mut socket = TcpStream::connect("localhost:1337").await?;
+    // Checks for the numbers on stdin, when there's no command
+    #[command(flatten)]
+    verify: verify::Args,
 
-    let exit = loop {
-        let mut line = String::new();
-        select! {
-            _  = signal::ctrl_c() => break 1,
-            res  = reader.read_line(&mut line) => {
-                match res {
-                    Ok(amnt) => {
-                        if amnt == 0 {
-                            break 0;
-                        }
-                        process_request(line.trim(), &mut socket).await?;
-                    }
-                    Err(err) => {
-                        error!("Error reading std: {err}");
-                        break 1;
-                    }
-                }
-            }
-        }
-    };
+    #[command(subcommand)]
+    command: Option<Command>,
+}
 
-    std::process::exit(exit);
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Classifies a list of numbers, writing a row for each and a summary at
+    /// the end
+    Batch(batch::Args),
+    /// Stresses the server from many connections at once, then reports
+    /// latencies and errors
+    Load(load::Args),
+    /// Runs a scripted suite of protocol cases, each over a connection of its
+    /// own, and shows where the answers differ from what they should be
+    Check(check::Args),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize)]
 struct Request {
     method: &'static str,
-    number: i64,
+    number: serde_json::Number,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 struct Response {
     method: String,
     prime: bool,
 }
 
-async fn process_request(line: &str, socket: &mut TcpStream) -> Result<()> {
-    let number: i64 = match line.parse() {
-        Ok(number) => number,
-        Err(_) => {
-            error!("Couldn't parse number");
-            return Ok(());
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::registry()
+        .with(fmt::layer())
+        .with(EnvFilter::from_default_env())
+        .init();
+    let args = Args::parse();
+
+    let run = async {
+        if args.command.is_some() && args.verify.enabled() {
+            bail!("--verify and --certify go after `batch`, or without a command");
         }
-    };
-    let request = Request {
-        method: "isPrime",
-        number,
-    };
-    debug!("Sending {:?}", request);
-    let bytes = serde_json::to_vec(&request)?;
-    socket.write_all(&bytes).await?;
-    debug!("Wrote request to socket");
-    let mut response_bytes = [0; 1024];
-    let got = socket.read(&mut response_bytes).await?;
-    debug!(
-        "Read {got} bytes from socket: {}",
-        String::from_utf8_lossy(&response_bytes)
-    );
-    let response: Response = serde_json::from_slice(&response_bytes[..got])?;
-    if response.method == "isPrime" {
-        if response.prime {
-            println!("{number} is prime");
-        } else {
-            println!("{number} is not prime");
+        match args.command {
+            None => {
+                let client = Client::connect(&args.address).await?;
+                let certifier = certifier(&args.address, args.verify).await?;
+                let summary = batch::run(
+                    &client,
+                    certifier.as_ref(),
+                    stdin(),
+                    stdout(),
+                    Format::Text,
+                    args.max_in_flight,
+                    args.verify.verify,
+                )
+                .await?;
+                if summary.disagreements > Some(0) {
+                    eprintln!("{summary}");
+                    std::process::exit(1);
+                }
+            }
+            Some(Command::Batch(batch)) => {
+                let client = Client::connect(&args.address).await?;
+                let certifier = certifier(&args.address, batch.verify).await?;
+                let input: Box<dyn AsyncRead + Unpin> = if batch.input.as_os_str() == "-" {
+                    Box::new(stdin())
+                } else {
+                    Box::new(tokio::fs::File::open(&batch.input).await?)
+                };
+                let output: Box<dyn Write> = match &batch.output {
+                    Some(path) => Box::new(File::create(path)?),
+                    None => Box::new(stdout().lock()),
+                };
+                let output = BufWriter::new(output);
+                let summary = batch::run(
+                    &client,
+                    certifier.as_ref(),
+                    input,
+                    output,
+                    batch.format,
+                    args.max_in_flight,
+                    batch.verify.verify,
+                )
+                .await?;
+                eprintln!("{summary}");
+                if summary.disagreements > Some(0) {
+                    std::process::exit(1);
+                }
+            }
+            Some(Command::Load(load)) => {
+                let load = load::Load::new(&load, args.max_in_flight)?;
+                let report = load::run(&args.address, &load).await?;
+                println!("{report}");
+            }
+            Some(Command::Check(check)) => {
+                let outcomes = check::run(&args.address, &check).await?;
+                let failed = outcomes.iter().filter(|o| !o.passed).count();
+                println!("{} passed, {failed} failed", outcomes.len() - failed);
+                if failed > 0 {
+                    std::process::exit(1);
+                }
+            }
         }
-    } else {
-        error!("Invalid response method! ({})", response.method);
+        anyhow::Ok(())
+    };
+    select! {
+        _ = signal::ctrl_c() => std::process::exit(1),
+        done = run => done,
+    }
+}
+
+/// A second connection to ask for certificates on, if `verify` wants them,
+/// so a server that doesn't take `certifyPrime` can hang up on it without
+/// taking the `isPrime` answers with it
+async fn certifier(address: &str, verify: verify::Args) -> Result<Option<Client>> {
+    if !verify.certify {
+        return Ok(None);
+    }
+    Ok(Some(Client::connect(address).await?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn cli() {
+        Args::command().debug_assert();
     }
-    Ok(())
 }
@@ -0,0 +1,281 @@
+use prime_time::certificate::{Certificate, Invalid};
+use prime_time::number::Number;
+use rug::Integer;
+use rug::integer::IsPrime;
+use serde::Deserialize;
+use std::fmt;
+
+// Enough rounds that GMP calling a composite prime is out of the question
+const REPS: u32 = 50;
+
+#[derive(Debug, Clone, Copy, Default, clap::Args)]
+#[group(skip)]
+pub struct Args {
+    /// Test every number locally too, and report wherever the server disagrees
+    #[arg(long)]
+    pub verify: bool,
+
+    /// Ask for a certificate for every number the server calls prime, and
+    /// check it
+    #[arg(long)]
+    pub certify: bool,
+}
+
+impl Args {
+    pub fn enabled(&self) -> bool {
+        self.verify || self.certify
+    }
+}
+
+/// Whether `number` is prime, by GMP rather than the server, or `None` if
+/// it can't be read as a number
+pub fn is_prime(number: &serde_json::Number) -> Option<bool> {
+    match Number::parse(&number.to_string()).ok()? {
+        Number::Integer(n) => Some(n > 1 && n.is_probably_prime(REPS) != IsPrime::No),
+        // Huge integers are multiples of 10
+        Number::NonInteger(_) | Number::Huge(_) => Some(false),
+    }
+}
+
+/// Whether `certifyPrime` takes `number`, which has to be an integer
+pub fn certifiable(number: &serde_json::Number) -> bool {
+    matches!(Number::parse(&number.to_string()), Ok(Number::Integer(_)))
+}
+
+/// Whether `line` is an answer to `certifyPrime` at all, rather than the
+/// server refusing the method
+pub fn answers_certify(line: &str) -> bool {
+    serde_json::from_str::<serde_json::Value>(line)
+        .is_ok_and(|answer| answer["method"] == "certifyPrime")
+}
+
+#[derive(Debug, Deserialize)]
+struct Certified {
+    method: String,
+    certificate: Option<Certificate>,
+    prime: Option<bool>,
+    error: Option<String>,
+}
+
+/// Where the server and the local checks part ways
+#[derive(Debug, PartialEq, Eq)]
+pub enum Problem {
+    /// `isPrime` disagrees with the local test
+    Local { local: bool },
+    /// `certifyPrime` disagrees with `isPrime`
+    Certify { certified: bool },
+    /// A certificate that doesn't prove anything
+    BadCertificate(Invalid),
+    /// A certificate for some other number
+    WrongNumber(Integer),
+    /// `certifyPrime` sent something other than an answer to it
+    BadCertifyResponse,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let prime = |prime: bool| if prime { "prime" } else { "not prime" };
+        match self {
+            Self::Local { local } => write!(f, "GMP says it's {}", prime(*local)),
+            Self::Certify { certified } => {
+                write!(f, "certifyPrime says it's {}", prime(*certified))
+            }
+            Self::BadCertificate(invalid) => write!(f, "The certificate is invalid: {invalid}"),
+            Self::WrongNumber(n) => write!(f, "The certificate is for {n}"),
+            Self::BadCertifyResponse => write!(f, "certifyPrime's answer makes no sense"),
+        }
+    }
+}
+
+/// A disagreement, with everything that went into it
+#[derive(Debug)]
+pub struct Discrepancy {
+    pub number: serde_json::Number,
+    /// What the server answered to `isPrime`
+    pub response: String,
+    /// What the server answered to `certifyPrime`, if it was asked
+    pub certified: Option<String>,
+    pub problem: Problem,
+}
+
+impl fmt::Display for Discrepancy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let number = &self.number;
+        writeln!(f, "Disagreement over {number}: {}", self.problem)?;
+        writeln!(
+            f,
+            "  sent:     {{\"method\":\"isPrime\",\"number\":{number}}}"
+        )?;
+        write!(f, "  received: {}", self.response)?;
+        if let Some(certified) = &self.certified {
+            writeln!(f)?;
+            writeln!(
+                f,
+                "  sent:     {{\"method\":\"certifyPrime\",\"number\":{number}}}"
+            )?;
+            write!(f, "  received: {certified}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks the server's verdict that `number` is `prime`, which it gave in
+/// `response`, against the `local` test and what it answered to
+/// `certifyPrime`
+pub fn check(
+    number: &serde_json::Number,
+    prime: bool,
+    response: &str,
+    local: Option<bool>,
+    certified: Option<String>,
+) -> Vec<Discrepancy> {
+    let mut problems = vec![];
+    if let Some(local) = local
+        && local != prime
+    {
+        problems.push(Problem::Local { local });
+    }
+    if let Some(line) = &certified {
+        problems.extend(check_certificate(number, prime, line));
+    }
+    problems
+        .into_iter()
+        .map(|problem| Discrepancy {
+            number: number.clone(),
+            response: response.to_string(),
+            certified: certified.clone(),
+            problem,
+        })
+        .collect()
+}
+
+fn check_certificate(number: &serde_json::Number, prime: bool, line: &str) -> Option<Problem> {
+    let certified = match serde_json::from_str::<Certified>(line) {
+        Ok(certified) if certified.method == "certifyPrime" => certified,
+        _ => return Some(Problem::BadCertifyResponse),
+    };
+    if let Some(reason) = certified.error {
+        // Out of time, which says nothing either way
+        tracing::warn!("No certificate for {number}: {reason}");
+        return None;
+    }
+    let Some(certificate) = certified.certificate else {
+        return match certified.prime {
+            Some(false) if prime => Some(Problem::Certify { certified: false }),
+            Some(false) => None,
+            _ => Some(Problem::BadCertifyResponse),
+        };
+    };
+    if !prime {
+        return Some(Problem::Certify { certified: true });
+    }
+    let n = match Number::parse(&number.to_string()) {
+        Ok(Number::Integer(n)) => n,
+        _ => return Some(Problem::WrongNumber(certificate.n().clone())),
+    };
+    if *certificate.n() != n {
+        return Some(Problem::WrongNumber(certificate.n().clone()));
+    }
+    certificate.verify().err().map(Problem::BadCertificate)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn number(text: &str) -> serde_json::Number {
+        text.parse().unwrap()
+    }
+
+    fn problems(n: &str, prime: bool, certified: Option<&str>) -> Vec<Problem> {
+        let n = number(n);
+        let local = is_prime(&n);
+        check(&n, prime, "response", local, certified.map(String::from))
+            .into_iter()
+            .map(|d| d.problem)
+            .collect()
+    }
+
+    #[test]
+    fn local_test() {
+        assert_eq!(is_prime(&number("7")), Some(true));
+        assert_eq!(is_prime(&number("7.0")), Some(true));
+        assert_eq!(is_prime(&number("7.5")), Some(false));
+        assert_eq!(is_prime(&number("-7")), Some(false));
+        assert_eq!(is_prime(&number("1e400")), Some(false));
+        assert_eq!(
+            is_prime(&number("170141183460469231731687303715884105727")),
+            Some(true)
+        );
+        assert_eq!(is_prime(&number("1e1000000000")), Some(false));
+        assert!(!certifiable(&number("1e1000000000")));
+        assert!(certifiable(&number("7.0")));
+        assert!(!certifiable(&number("7.5")));
+    }
+
+    #[test]
+    fn wrong_verdicts() {
+        assert_eq!(problems("7", true, None), []);
+        assert_eq!(
+            problems("561", true, None),
+            [Problem::Local { local: false }]
+        );
+        assert_eq!(
+            problems("97", false, None),
+            [Problem::Local { local: true }]
+        );
+    }
+
+    #[test]
+    fn certificates() {
+        let good = r#"{"method":"certifyPrime","certificate":{"small":97}}"#;
+        assert_eq!(problems("97", true, Some(good)), []);
+        let other = r#"{"method":"certifyPrime","certificate":{"small":89}}"#;
+        assert_eq!(
+            problems("97", true, Some(other)),
+            [Problem::WrongNumber(89.into())]
+        );
+        let bad = r#"{"method":"certifyPrime","certificate":{"small":91}}"#;
+        assert_eq!(
+            problems("91", true, Some(bad)),
+            [
+                Problem::Local { local: false },
+                Problem::BadCertificate(Invalid::Composite(91.into()))
+            ]
+        );
+        let composite = r#"{"method":"certifyPrime","prime":false}"#;
+        assert_eq!(problems("91", false, Some(composite)), []);
+        assert_eq!(
+            problems("97", true, Some(composite)),
+            [Problem::Certify { certified: false }]
+        );
+        let gave_up = r#"{"method":"certifyPrime","error":"Timed out"}"#;
+        assert_eq!(problems("97", true, Some(gave_up)), []);
+        assert_eq!(
+            problems("97", true, Some("malformed request")),
+            [Problem::BadCertifyResponse]
+        );
+        assert!(answers_certify(good));
+        assert!(answers_certify(
+            r#"{"method":"certifyPrime","certificate":7}"#
+        ));
+        assert!(!answers_certify("malformed request"));
+        assert!(!answers_certify(r#"{"error":{"code":"unknown_method"}}"#));
+    }
+
+    #[test]
+    fn discrepancies_show_the_exchange() {
+        let shown = check(
+            &number("561"),
+            true,
+            r#"{"method":"isPrime","prime":true}"#,
+            Some(false),
+            Some(r#"{"method":"certifyPrime","prime":false}"#.into()),
+        );
+        let shown: Vec<_> = shown.iter().map(|d| d.to_string()).collect();
+        assert_eq!(shown.len(), 2);
+        assert!(shown[0].starts_with("Disagreement over 561: GMP says it's not prime\n"));
+        assert!(shown[0].contains(r#"{"method":"isPrime","number":561}"#));
+        assert!(shown[1].contains(r#"{"method":"certifyPrime","prime":false}"#));
+    }
+}